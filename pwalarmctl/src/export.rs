@@ -0,0 +1,207 @@
+// Turns the alarm list from FetchAlarms into something other
+// programs can read: iCalendar, JSON, or a pwalarmd.toml fragment
+use std::str::FromStr;
+
+use chrono::{Datelike, Local, NaiveDate, Utc, Weekday};
+use serde_derive::Serialize;
+
+use crate::{beprint, days_to_date, protobuf_sock::AlarmInfo};
+
+// Lines longer than this many octets get folded
+const ICS_LINE_MAX: usize = 75;
+
+// Mirrors the [[Alarm]] tables in pwalarmd.toml; time is a string in
+// JSON and a TOML date-time in the config fragment
+#[derive(Serialize)]
+struct ExportAlarm<T> {
+    id: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    time: Option<T>,
+    repeat: Option<Vec<String>>,
+    schedule: Option<String>,
+    rrule: Option<String>,
+    sound: Option<String>,
+    icon: Option<String>,
+    snooze: Option<u64>,
+    max_ring: Option<u64>,
+    volume: Option<u32>,
+    ramp_seconds: Option<u64>,
+    enabled: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ExportFile<T> {
+    #[serde(rename = "Alarm")]
+    alarms: Vec<ExportAlarm<T>>,
+}
+
+pub fn to_json(alarms: &[AlarmInfo]) -> Result<String, Box<dyn std::error::Error>> {
+    let v: Vec<ExportAlarm<String>> = alarms
+        .iter()
+        .map(|a| export_alarm(a, time_str(a)))
+        .collect();
+    Ok(serde_json::to_string_pretty(&v)? + "\n")
+}
+
+pub fn to_toml(alarms: &[AlarmInfo]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut v = vec![];
+    for a in alarms {
+        let t = match time_str(a) {
+            Some(t) => Some(toml::value::Datetime::from_str(&t)?),
+            None => None,
+        };
+        v.push(export_alarm(a, t));
+    }
+    Ok(toml::to_string(&ExportFile { alarms: v })?)
+}
+
+pub fn to_ics(alarms: &[AlarmInfo]) -> String {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//pwalarmd//pwalarmctl//EN".to_string(),
+    ];
+    for a in alarms {
+        if a.has_schedule() {
+            beprint(&format!(
+                "skipping {}: cron schedules can't be expressed in iCalendar",
+                a.id()
+            ));
+            continue;
+        }
+        if a.has_enabled() && !a.enabled() {
+            beprint(&format!("skipping {}: alarm is disabled", a.id()));
+            continue;
+        }
+        let t = a.time();
+        let (date, rrule) = if a.has_rrule() {
+            let r = a.rrule();
+            (
+                days_to_date(a.date()),
+                Some(r.strip_prefix("RRULE:").unwrap_or(r).to_string()),
+            )
+        } else if a.has_date() {
+            (days_to_date(a.date()), None)
+        } else if a.repeat.is_empty() {
+            (Local::now().date_naive(), Some("FREQ=DAILY".to_string()))
+        } else {
+            let days: Vec<String> = a.repeat.iter().map(|d| d.to_ascii_uppercase()).collect();
+            // DTSTART is always an occurrence, so it has to be on one of the days
+            (
+                first_matching_day(&days),
+                Some(format!("FREQ=WEEKLY;BYDAY={}", days.join(","))),
+            )
+        };
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}", a.id()));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!(
+            "DTSTART:{}T{:02}{:02}{:02}",
+            date.format("%Y%m%d"),
+            t / 3600,
+            (t / 60) % 60,
+            t % 60
+        ));
+        if let Some(r) = rrule {
+            lines.push(format!("RRULE:{}", r));
+        }
+        if a.has_title() {
+            lines.push(format!("SUMMARY:{}", escape(a.title())));
+        }
+        if a.has_desc() {
+            lines.push(format!("DESCRIPTION:{}", escape(a.desc())));
+        }
+        lines.push("BEGIN:VALARM".to_string());
+        lines.push("ACTION:DISPLAY".to_string());
+        lines.push(format!(
+            "DESCRIPTION:{}",
+            escape(if a.has_title() { a.title() } else { "Alarm" })
+        ));
+        lines.push("TRIGGER:PT0S".to_string());
+        lines.push("END:VALARM".to_string());
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|l| fold(l) + "\r\n").collect()
+}
+
+fn export_alarm<T>(a: &AlarmInfo, time: Option<T>) -> ExportAlarm<T> {
+    ExportAlarm {
+        id: a.id.clone(),
+        title: a.title.clone(),
+        description: a.desc.clone(),
+        time,
+        repeat: if a.repeat.is_empty() {
+            None
+        } else {
+            Some(a.repeat.clone())
+        },
+        schedule: a.schedule.clone(),
+        rrule: a.rrule.clone(),
+        sound: a.sound.clone(),
+        icon: a.icon.clone(),
+        snooze: a.snooze,
+        max_ring: a.max_ring,
+        volume: a.volume,
+        ramp_seconds: a.ramp_seconds,
+        enabled: a.enabled,
+    }
+}
+
+// HH:MM:SS, with the date in front for one-shot and rrule alarms
+fn time_str(a: &AlarmInfo) -> Option<String> {
+    let t = a.time?;
+    let hms = format!("{:02}:{:02}:{:02}", t / 3600, (t / 60) % 60, t % 60);
+    Some(if a.has_date() {
+        format!("{}T{}", days_to_date(a.date()), hms)
+    } else {
+        hms
+    })
+}
+
+// The first day from today on whose weekday is in days (MO, TU, ...)
+fn first_matching_day(days: &[String]) -> NaiveDate {
+    let today = Local::now().date_naive();
+    today
+        .iter_days()
+        .take(7)
+        .find(|d| days.contains(&weekday_code(d.weekday()).to_string()))
+        .unwrap_or(today)
+}
+
+fn weekday_code(w: Weekday) -> &'static str {
+    match w {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// Splits a line into ICS_LINE_MAX octet pieces, without breaking up
+// characters; continuations start with a space
+fn fold(line: &str) -> String {
+    let mut out = String::new();
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > ICS_LINE_MAX {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out
+}
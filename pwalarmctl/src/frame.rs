@@ -0,0 +1 @@
+../../src/frame.rs
\ No newline at end of file
@@ -0,0 +1 @@
+../../src/ical.rs
\ No newline at end of file
@@ -1,18 +1,17 @@
-use std::{
-    io::{Read, Write},
-    os::unix::net::UnixStream,
-    process::exit,
-    str::FromStr,
-};
+use std::{os::unix::net::UnixStream, process::exit, str::FromStr};
 
+use chrono::{Local, NaiveDate, TimeZone, Timelike};
 use clap::{Parser, Subcommand};
 use colored::Colorize;
 use protobuf::Message;
 use protobuf_sock::{ErrorReason, RequestSuccessWithData};
 
+mod export;
+mod frame;
+mod ical;
+#[allow(renamed_and_removed_lints)]
 mod protobuf_sock;
-
-const BUF_SIZE: usize = 16384;
+mod watch;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -23,7 +22,6 @@ struct Cli {
     cmd: CliCommand,
 }
 
-// TODO: list alarms, allow removal of alarms (id by hash of alarm)
 #[derive(Subcommand)]
 enum CliCommand {
     #[command(about = "Print current settings")]
@@ -34,25 +32,113 @@ enum CliCommand {
     Set { attribute: String, value: String },
     #[command(about = "Kill pwalarmd")]
     Kill,
+    #[command(about = "Write the running config back to the config file")]
+    Save,
+    #[command(about = "Show whether the last config reload worked")]
+    Status,
     #[command(about = "List current alarms")]
     List,
     #[command(about = "Delete alarm")]
-    Remove { hash: String },
+    Remove { id: String },
     #[command(about = "Create new alarm")]
     Add {
+        #[clap(long)]
+        id: Option<String>,
+        #[clap(short = 'T', long)]
+        title: Option<String>,
+        #[clap(short, long)]
+        desc: Option<String>,
+        #[clap(short = 't', long, required_unless_present = "schedule")]
+        time: Option<String>,
+        #[clap(short, long)]
+        repeat: Option<String>,
+        #[clap(short = 'c', long, conflicts_with_all = ["time", "repeat"])]
+        schedule: Option<String>,
+        #[clap(long, conflicts_with_all = ["schedule", "repeat"])]
+        rrule: Option<String>,
+        #[clap(short, long)]
+        sound: Option<String>,
+        #[clap(short, long)]
+        icon: Option<String>,
+        #[clap(short = 'z', long)]
+        snooze: Option<String>,
+        #[clap(short, long)]
+        max_ring: Option<String>,
+        #[clap(short, long, value_parser = clap::value_parser!(u32).range(0..=100))]
+        volume: Option<u32>,
+        #[clap(long)]
+        ramp: Option<String>,
+    },
+    #[command(about = "Add the alarms from an iCalendar (.ics) file")]
+    Import { path: String },
+    #[command(about = "Print all alarms as iCalendar, JSON or a TOML config fragment")]
+    Export {
+        #[clap(short, long, value_parser = ["ics", "json", "toml"], default_value = "toml")]
+        format: String,
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    #[command(about = "Change an existing alarm")]
+    Edit {
+        id: String,
         #[clap(short = 'T', long)]
         title: Option<String>,
         #[clap(short, long)]
         desc: Option<String>,
         #[clap(short = 't', long)]
-        time: String,
+        time: Option<String>,
+        #[clap(long, conflicts_with = "schedule")]
+        no_date: bool,
         #[clap(short, long)]
         repeat: Option<String>,
+        #[clap(short = 'c', long, conflicts_with_all = ["time", "repeat"])]
+        schedule: Option<String>,
+        #[clap(long, conflicts_with_all = ["schedule", "repeat"])]
+        rrule: Option<String>,
+        #[clap(short, long)]
+        sound: Option<String>,
+        #[clap(short, long)]
+        icon: Option<String>,
+    },
+    #[command(about = "Turn an alarm back on")]
+    Enable { id: String },
+    #[command(about = "Turn an alarm off without deleting it")]
+    Disable { id: String },
+    #[command(about = "Skip the next occurrences of a repeating alarm")]
+    Skip {
+        id: String,
+        #[clap(short, long)]
+        count: Option<u32>,
+    },
+    #[command(about = "Start a countdown timer")]
+    Timer {
+        duration: String,
+        #[clap(short = 'T', long)]
+        title: Option<String>,
+        #[clap(short, long)]
+        desc: Option<String>,
         #[clap(short, long)]
         sound: Option<String>,
         #[clap(short, long)]
         icon: Option<String>,
     },
+    #[command(about = "List running timers")]
+    Timers,
+    #[command(about = "Cancel a running timer")]
+    Cancel { id: String },
+    #[command(about = "Snooze the alarm that rang last, or a specific alarm")]
+    Snooze {
+        id: Option<String>,
+        #[clap(short = 'f', long = "for")]
+        duration: Option<String>,
+    },
+    #[command(about = "Stop ringing alarms, or a specific alarm")]
+    Dismiss { id: Option<String> },
+    #[command(about = "Print what pwalarmd does as it happens, until it exits")]
+    Watch {
+        #[clap(short, long)]
+        json: bool,
+    },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -64,39 +150,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }));
     match res.cmd {
         CliCommand::Info => {
-            let mut socket = UnixStream::connect(&sock)?;
-            let mut r: RequestSuccessWithData;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::Sound)?;
-            r = recv_get(&mut socket)?;
-            println!("sound = {}", if r.has_st() { r.st() } else { "unknown" });
-            socket = UnixStream::connect(&sock)?;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::Poll)?;
-            r = recv_get(&mut socket)?;
-            println!("poll = {}", if r.has_ui() { r.ui() } else { 0 });
-            socket = UnixStream::connect(&sock)?;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::Notify)?;
-            r = recv_get(&mut socket)?;
-            println!("notify = {}", if r.has_bl() { r.bl() } else { false });
-            socket = UnixStream::connect(&sock)?;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::AppName)?;
-            r = recv_get(&mut socket)?;
-            println!("appname = {}", if r.has_st() { r.st() } else { "unknown" });
-            socket = UnixStream::connect(&sock)?;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::Daemon)?;
-            r = recv_get(&mut socket)?;
-            println!("daemon = {}", if r.has_bl() { r.bl() } else { false });
-            socket = UnixStream::connect(&sock)?;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::Tpfc)?;
-            r = recv_get(&mut socket)?;
-            println!("tpfc = {}", if r.has_sui() { r.sui() } else { 0 });
-            socket = UnixStream::connect(&sock)?;
-            send_get(&mut socket, protobuf_sock::GeneralInfoType::Tsfc)?;
-            r = recv_get(&mut socket)?;
-            println!("tsfc = {}", if r.has_sui() { r.sui() } else { 0 });
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_fag(protobuf_sock::FetchAllGeneral::new());
+            let mut resp = socket.request(sr)?;
+            if !resp.has_swg() {
+                beprint("server returned unreasonable response");
+                exit(127);
+            }
+            let g = resp.take_swg();
+            println!("sound = {}", g.sound.as_deref().unwrap_or("unknown"));
+            println!("poll = {}", g.poll.unwrap_or(0));
+            println!("notify = {}", g.notify.unwrap_or(false));
+            println!("appname = {}", g.appname.as_deref().unwrap_or("unknown"));
+            println!("daemon = {}", g.daemon.unwrap_or(false));
+            println!("tpfc = {}", g.tpfc.unwrap_or(0));
+            println!("tsfc = {}", g.tsfc.unwrap_or(0));
         }
         CliCommand::Get { attribute } => {
-            let mut socket = UnixStream::connect(&sock)?;
-            send_get(
+            let mut socket = Conn::open(&sock)?;
+            let resp = fetch_general(
                 &mut socket,
                 match attribute.as_str() {
                     "sound" => protobuf_sock::GeneralInfoType::Sound,
@@ -112,7 +185,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 },
             )?;
-            let resp = recv_get(&mut socket)?;
             if resp.has_st() {
                 println!("{}", resp.st());
             } else if resp.has_ui() {
@@ -127,7 +199,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         CliCommand::Set { attribute, value } => {
-            let mut socket = UnixStream::connect(&sock)?;
+            let mut socket = Conn::open(&sock)?;
             let mut req = protobuf_sock::SocketRequest::new();
             match attribute.as_str() {
                 "sound" => {
@@ -172,146 +244,481 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     exit(1);
                 }
             }
-            req.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-            socket.flush()?;
-            if recv(&mut socket)?.has_err() {
+            if socket.request(req)?.has_err() {
                 beprint("server error during value set");
                 std::process::exit(123);
             }
         }
         CliCommand::Kill => {
-            let mut socket = UnixStream::connect(&sock)?;
+            let mut socket = Conn::open(&sock)?;
             let mut sr = protobuf_sock::SocketRequest::new();
             sr.set_ks(protobuf_sock::KillSwitch::new());
-            sr.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-            socket.flush()?;
+            socket.send(sr)?;
         }
-        CliCommand::List => {
-            let mut socket = UnixStream::connect(&sock)?;
+        CliCommand::Save => {
+            let mut socket = Conn::open(&sock)?;
             let mut sr = protobuf_sock::SocketRequest::new();
-            sr.set_fa(protobuf_sock::FetchAlarms::new());
-            sr.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-            socket.flush()?;
-            let resp = recv(&mut socket)?;
-            if !resp.has_swa() {
-                beprint("could not receive alarms");
-                exit(124);
+            sr.set_sv(protobuf_sock::SaveConfig::new());
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to save config: {}", res.err()));
+                exit(115);
+            }
+        }
+        CliCommand::Status => {
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_frs(protobuf_sock::FetchLastReloadStatus::new());
+            let resp = socket.request(sr)?;
+            if !resp.has_swr() {
+                beprint("could not receive reload status");
+                exit(107);
             }
-            for m in &resp.swa().als {
+            let r = resp.swr();
+            match Local.timestamp_opt(r.time(), 0).single() {
+                Some(t) if r.has_time() => {
+                    let at = t.format("%Y-%m-%d %H:%M:%S");
+                    if r.applied() {
+                        println!("config reloaded at {}", at);
+                    } else {
+                        println!(
+                            "config reload at {} failed, keeping the previous config:",
+                            at
+                        );
+                        for e in &r.errors {
+                            println!("  {}", e);
+                        }
+                    }
+                }
+                _ => println!("config not reloaded since pwalarmd started"),
+            }
+        }
+        CliCommand::Watch { json } => {
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_sub(protobuf_sock::Subscribe::new());
+            if socket.request(sr)?.has_err() {
+                beprint("server refused to send events");
+                exit(122);
+            }
+            loop {
+                let resp = match socket.receive() {
+                    Ok(r) => r,
+                    // pwalarmd went away, after saying so if it could
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e.into()),
+                };
+                if !resp.has_ev() {
+                    continue;
+                }
+                if let Some(line) = watch::format(resp.ev(), json)? {
+                    println!("{}", line);
+                }
+            }
+        }
+        CliCommand::List => {
+            for m in &fetch_alarms(&sock)? {
                 let t = m.time();
                 println!(
-                    "{:8x}: \"{}\" @ {:02}:{:02}:{:02} (rep: {:?})",
-                    _hashalarms(m),
+                    "{:>8}: \"{}\" @ {}{}",
+                    m.id(),
                     m.title(),
-                    t / 3600,
-                    (t / 60) % 60,
-                    t % 60,
-                    m.repeat
+                    if m.has_schedule() {
+                        format!("cron \"{}\"", m.schedule())
+                    } else {
+                        format!(
+                            "{}{:02}:{:02}:{:02} ({})",
+                            if m.has_date() {
+                                format!("{} ", days_to_date(m.date()))
+                            } else {
+                                String::new()
+                            },
+                            t / 3600,
+                            (t / 60) % 60,
+                            t % 60,
+                            if m.has_rrule() {
+                                format!("rrule: {}", m.rrule())
+                            } else {
+                                format!("rep: {:?}", m.repeat)
+                            }
+                        )
+                    },
+                    if m.has_enabled() && !m.enabled() {
+                        " [disabled]"
+                    } else {
+                        ""
+                    }
                 );
             }
         }
-        CliCommand::Remove { hash } => {
-            // TODO: dedup with List
-            let h = u32::from_str_radix(&hash, 16)?;
-            let mut socket = UnixStream::connect(&sock)?;
+        CliCommand::Remove { id } => {
+            let mut socket = Conn::open(&sock)?;
             let mut sr = protobuf_sock::SocketRequest::new();
-            sr.set_fa(protobuf_sock::FetchAlarms::new());
-            sr.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-            socket.flush()?;
-            let resp = recv(&mut socket)?;
-            if !resp.has_swa() {
-                beprint("could not recieve original alarm list");
-                exit(123);
-            }
-            for m in &resp.swa().als {
-                if _hashalarms(m) == h {
-                    socket = UnixStream::connect(&sock)?;
-                    let mut sr = protobuf_sock::SocketRequest::new();
-                    let mut ins = protobuf_sock::RemoveAlarm::new();
-                    ins.al = protobuf::MessageField(Some(Box::new(m.clone())));
-                    sr.set_ra(ins);
-                    sr.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-                    socket.flush()?;
-                    let res = recv(&mut socket)?;
-                    if res.has_err() {
-                        beprint(&format!("failed to remove alarm: {}", res.err()));
-                        exit(121);
-                    }
-                    exit(0);
-                }
+            let mut ins = protobuf_sock::RemoveAlarm::new();
+            ins.id = Some(id);
+            sr.set_ra(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to remove alarm: {}", res.err()));
+                exit(121);
             }
-            beprint("alarm does not exist");
-            exit(122);
         }
         CliCommand::Add {
+            id,
             title,
             desc,
             time,
             repeat,
+            schedule,
+            rrule,
             sound,
             icon,
+            snooze,
+            max_ring,
+            volume,
+            ramp,
         } => {
-            let tc = time
-                .splitn(3, ':')
-                .map(|z| {
-                    u32::from_str(z).unwrap_or_else(|_| {
-                        beprint("invalid time specifier");
-                        exit(121)
-                    })
-                })
-                .collect::<Vec<_>>();
-            let tv: u32 = match tc.len() {
-                2 => tc[0] * 3600 + tc[1] * 60,
-                3 => tc[0] * 3600 + tc[1] * 60 + tc[2],
-                _ => {
-                    beprint("invalid time specifier");
-                    exit(121);
+            let (date, tv) = match time {
+                Some(t) => {
+                    let (date, tv) = parse_alarm_time(&t);
+                    (date, Some(tv))
                 }
+                None => (None, None),
             };
             let mut v = vec![];
             if let Some(z) = repeat {
-                if z.len() != 0 {
+                if !z.is_empty() {
                     for m in z.split(",") {
                         v.push(m.to_string());
                     }
                 }
             }
-            let mut socket = UnixStream::connect(&sock)?;
+            let mut socket = Conn::open(&sock)?;
             let mut sr = protobuf_sock::SocketRequest::new();
             let mut qu = protobuf_sock::NewAlarm::new();
             let mut al = protobuf_sock::AlarmInfo::new();
+            al.id = id;
             al.title = title;
             al.desc = desc;
             al.repeat = v;
             al.sound = sound;
             al.icon = icon;
-            al.time = Some(tv);
+            al.time = tv;
+            al.date = date;
+            al.schedule = schedule;
+            al.rrule = rrule;
+            al.snooze = snooze.map(|z| parse_duration(&z));
+            al.max_ring = max_ring.map(|z| parse_duration(&z));
+            al.volume = volume;
+            al.ramp_seconds = ramp.map(|z| parse_duration(&z));
             qu.al = protobuf::MessageField(Some(Box::new(al)));
             sr.set_na(qu);
-            sr.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-            socket.flush()?;
-            if recv(&mut socket)?.has_err() {
-                beprint("unable to create alarm");
+            let mut res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("unable to create alarm: {}", res.err()));
                 exit(120);
             }
+            if res.has_swd() && res.swd().has_st() {
+                println!("{}", res.take_swd().take_st());
+            }
+        }
+        CliCommand::Export { format, output } => {
+            let als = fetch_alarms(&sock)?;
+            let out = match format.as_str() {
+                "ics" => export::to_ics(&als),
+                "json" => export::to_json(&als)?,
+                _ => export::to_toml(&als)?,
+            };
+            match output {
+                Some(p) => std::fs::write(&p, out).unwrap_or_else(|e| {
+                    beprint(&format!("unable to write {}: {}", p, e));
+                    exit(108)
+                }),
+                None => print!("{}", out),
+            }
+        }
+        CliCommand::Import { path } => {
+            let text = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                beprint(&format!("unable to read {}: {}", path, e));
+                exit(109)
+            });
+            let alarms = ical::parse_ics(&text).unwrap_or_else(|e| {
+                beprint(&format!("unable to parse {}: {}", path, e));
+                exit(109)
+            });
+            let now = Local::now().naive_local();
+            let mut socket = Conn::open(&sock)?;
+            for ia in alarms {
+                let name = ia.title.clone().unwrap_or("(untitled)".to_string());
+                if ia.rrule.is_none() && ia.at <= now {
+                    println!("skipping \"{}\": already happened", name);
+                    continue;
+                }
+                let mut al = protobuf_sock::AlarmInfo::new();
+                al.id = ia.id;
+                al.title = ia.title;
+                al.desc = ia.description;
+                al.time = Some(ia.at.num_seconds_from_midnight());
+                al.date = Some(date_to_days(ia.at.date()));
+                al.rrule = ia.rrule;
+                let mut qu = protobuf_sock::NewAlarm::new();
+                qu.al = protobuf::MessageField(Some(Box::new(al)));
+                let mut sr = protobuf_sock::SocketRequest::new();
+                sr.set_na(qu);
+                let mut res = socket.request(sr)?;
+                if res.has_err() {
+                    // importing the same file twice keeps the first copy
+                    if res.err().er() == ErrorReason::AlreadyExists {
+                        println!("skipping \"{}\": already imported", name);
+                        continue;
+                    }
+                    beprint(&format!("unable to import \"{}\": {}", name, res.err()));
+                    exit(109);
+                }
+                if res.has_swd() && res.swd().has_st() {
+                    println!("{}: \"{}\"", res.take_swd().take_st(), name);
+                }
+            }
+        }
+        CliCommand::Edit {
+            id,
+            title,
+            desc,
+            time,
+            no_date,
+            repeat,
+            schedule,
+            rrule,
+            sound,
+            icon,
+        } => {
+            let mut ins = protobuf_sock::ModifyAlarm::new();
+            ins.id = Some(id);
+            ins.title = title;
+            ins.desc = desc;
+            if let Some(t) = time {
+                let (date, tv) = parse_alarm_time(&t);
+                ins.time = Some(tv);
+                ins.date = date;
+            }
+            if no_date {
+                ins.set_undated(true);
+            }
+            if let Some(z) = repeat {
+                let mut r = protobuf_sock::RepeatDays::new();
+                if !z.is_empty() {
+                    for m in z.split(',') {
+                        r.days.push(m.to_string());
+                    }
+                }
+                ins.repeat = protobuf::MessageField(Some(Box::new(r)));
+            }
+            ins.schedule = schedule;
+            ins.rrule = rrule;
+            ins.sound = sound;
+            ins.icon = icon;
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_ma(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                match res.err().msg {
+                    Some(ref m) => beprint(&format!("failed to modify alarm: {}", m)),
+                    None => beprint(&format!("failed to modify alarm: {}", res.err())),
+                }
+                exit(116);
+            }
+        }
+        CliCommand::Enable { id } => {
+            let mut ins = protobuf_sock::EnableAlarm::new();
+            ins.id = Some(id);
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_ea(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to enable alarm: {}", res.err()));
+                exit(114);
+            }
+        }
+        CliCommand::Disable { id } => {
+            let mut ins = protobuf_sock::DisableAlarm::new();
+            ins.id = Some(id);
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_da(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to disable alarm: {}", res.err()));
+                exit(113);
+            }
+        }
+        CliCommand::Skip { id, count } => {
+            let mut ins = protobuf_sock::SkipNext::new();
+            ins.id = Some(id);
+            ins.count = count;
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_sk(ins);
+            let mut res = socket.request(sr)?;
+            if res.has_err() {
+                match res.err().msg {
+                    Some(ref m) => beprint(&format!("failed to skip alarm: {}", m)),
+                    None => beprint(&format!("failed to skip alarm: {}", res.err())),
+                }
+                exit(112);
+            }
+            if res.has_swd() && res.swd().has_st() {
+                println!("next ring on {}", res.take_swd().take_st());
+            }
+        }
+        CliCommand::Timer {
+            duration,
+            title,
+            desc,
+            sound,
+            icon,
+        } => {
+            let mut ins = protobuf_sock::NewTimer::new();
+            ins.duration = Some(parse_duration(&duration));
+            ins.title = title;
+            ins.desc = desc;
+            ins.sound = sound;
+            ins.icon = icon;
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_nt(ins);
+            let mut res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("unable to start timer: {}", res.err()));
+                exit(111);
+            }
+            if res.has_swd() && res.swd().has_st() {
+                println!("{}", res.take_swd().take_st());
+            }
+        }
+        CliCommand::Timers => {
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_ft(protobuf_sock::FetchTimers::new());
+            let resp = socket.request(sr)?;
+            if !resp.has_swt() {
+                beprint("could not receive timers");
+                exit(124);
+            }
+            for m in &resp.swt().tms {
+                let r = m.remaining();
+                println!(
+                    "{:>8}: \"{}\" {:02}:{:02}:{:02} left",
+                    m.id(),
+                    m.title(),
+                    r / 3600,
+                    (r / 60) % 60,
+                    r % 60
+                );
+            }
+        }
+        CliCommand::Cancel { id } => {
+            let mut ins = protobuf_sock::CancelTimer::new();
+            ins.id = Some(id);
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_ct(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to cancel timer: {}", res.err()));
+                exit(110);
+            }
+        }
+        CliCommand::Snooze { id, duration } => {
+            let mut ins = protobuf_sock::Snooze::new();
+            ins.id = id;
+            ins.duration = duration.map(|z| parse_duration(&z));
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_sz(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to snooze alarm: {}", res.err()));
+                exit(119);
+            }
+        }
+        CliCommand::Dismiss { id } => {
+            let mut ins = protobuf_sock::Dismiss::new();
+            ins.id = id;
+            let mut socket = Conn::open(&sock)?;
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_dm(ins);
+            let res = socket.request(sr)?;
+            if res.has_err() {
+                beprint(&format!("failed to dismiss alarm: {}", res.err()));
+                exit(117);
+            }
         }
     }
     Ok(())
 }
 
-fn _hashalarms(a: &protobuf_sock::AlarmInfo) -> u32 {
-    crc32fast::hash(
-        format!(
-            "{},{},{},{:?},{},{}",
-            a.title(),
-            a.desc(),
-            a.time(),
-            a.repeat,
-            a.sound(),
-            a.icon()
-        )
-        .as_bytes(),
-    )
+// Accepts a number of seconds, minutes or hours (30s, 10m, 1h);
+// a bare number is taken as minutes
+fn parse_duration(dur: &str) -> u64 {
+    let (n, mul) = match dur.char_indices().last() {
+        Some((i, 's')) => (&dur[..i], 1),
+        Some((i, 'm')) => (&dur[..i], 60),
+        Some((i, 'h')) => (&dur[..i], 3600),
+        _ => (dur, 60),
+    };
+    u64::from_str(n)
+        .ok()
+        .and_then(|z| z.checked_mul(mul))
+        .unwrap_or_else(|| {
+            beprint("invalid duration specifier");
+            exit(118)
+        })
+}
+
+// Accepts HH:MM[:SS], optionally preceded by a YYYY-MM-DD date
+// (separated by a 'T' or a space) for one-shot alarms
+fn parse_alarm_time(time: &str) -> (Option<i32>, u32) {
+    let (date, time) = match time.split_once(['T', ' ']) {
+        Some((d, t)) => (
+            Some(
+                NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap_or_else(|_| {
+                    beprint("invalid date specifier");
+                    exit(121)
+                }),
+            ),
+            t,
+        ),
+        None => (None, time),
+    };
+    let tc = time
+        .splitn(3, ':')
+        .map(|z| {
+            u32::from_str(z).unwrap_or_else(|_| {
+                beprint("invalid time specifier");
+                exit(121)
+            })
+        })
+        .collect::<Vec<_>>();
+    let in_range = tc.first().is_some_and(|h| *h <= 23) && tc[1..].iter().all(|z| *z <= 59);
+    let tv: u32 = match tc.len() {
+        2 if in_range => tc[0] * 3600 + tc[1] * 60,
+        3 if in_range => tc[0] * 3600 + tc[1] * 60 + tc[2],
+        _ => {
+            beprint("invalid time specifier");
+            exit(121);
+        }
+    };
+    (date.map(date_to_days), tv)
+}
+
+fn date_to_days(d: NaiveDate) -> i32 {
+    (d - NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()).num_days() as i32
+}
+
+fn days_to_date(days: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).unwrap() + chrono::Duration::days(days.into())
 }
 
 fn beprint(msg: &str) {
@@ -319,29 +726,70 @@ fn beprint(msg: &str) {
     eprintln!(": {}", msg.bright_red());
 }
 
-fn send_get(
-    socket: &mut UnixStream,
+fn fetch_alarms(sock: &str) -> Result<Vec<protobuf_sock::AlarmInfo>, Box<dyn std::error::Error>> {
+    let mut socket = Conn::open(sock)?;
+    let mut sr = protobuf_sock::SocketRequest::new();
+    sr.set_fa(protobuf_sock::FetchAlarms::new());
+    let mut resp = socket.request(sr)?;
+    if !resp.has_swa() {
+        beprint("could not receive alarms");
+        exit(124);
+    }
+    Ok(resp.take_swa().als)
+}
+
+// A connection to pwalarmd, good for any number of requests
+struct Conn {
+    stream: UnixStream,
+    last_rid: u64,
+}
+
+impl Conn {
+    fn open(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+            last_rid: 0,
+        })
+    }
+
+    // For requests that don't get a response, like KillSwitch
+    fn send(&mut self, mut req: protobuf_sock::SocketRequest) -> std::io::Result<()> {
+        self.last_rid += 1;
+        req.rid = Some(self.last_rid);
+        frame::write_frame(&mut self.stream, &req)
+    }
+
+    fn request(
+        &mut self,
+        req: protobuf_sock::SocketRequest,
+    ) -> Result<protobuf_sock::SocketResponse, Box<dyn std::error::Error>> {
+        self.send(req)?;
+        let resp = self.receive()?;
+        // an old pwalarmd doesn't echo it
+        if resp.rid.is_some_and(|r| r != self.last_rid) {
+            beprint("server answered a different request");
+            exit(2);
+        }
+        Ok(resp)
+    }
+
+    // The next response or event, whatever it answers
+    fn receive(&mut self) -> std::io::Result<protobuf_sock::SocketResponse> {
+        let body = frame::read_frame(&mut self.stream)?;
+        protobuf_sock::SocketResponse::parse_from_bytes(&body)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn fetch_general(
+    socket: &mut Conn,
     ty: protobuf_sock::GeneralInfoType,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<RequestSuccessWithData, Box<dyn std::error::Error>> {
     let mut req = protobuf_sock::SocketRequest::new();
     let mut g = protobuf_sock::FetchGeneralInfo::new();
     g.set_git(ty);
     req.set_fgi(g);
-    req.write_to(&mut protobuf::CodedOutputStream::new(socket))?;
-    socket.flush()?;
-    Ok(())
-}
-
-fn recv(
-    socket: &mut UnixStream,
-) -> Result<protobuf_sock::SocketResponse, Box<dyn std::error::Error>> {
-    let mut buf = [0u8; BUF_SIZE];
-    let rc = socket.read(&mut buf)?;
-    Ok(protobuf_sock::SocketResponse::parse_from_bytes(&buf[..rc])?)
-}
-
-fn recv_get(socket: &mut UnixStream) -> Result<RequestSuccessWithData, Box<dyn std::error::Error>> {
-    let mut resp = recv(socket)?;
+    let mut resp = socket.request(req)?;
     if resp.has_err() {
         match ri_to_rr(
             resp.take_err()
@@ -0,0 +1,75 @@
+// Lines for `pwalarmctl watch`: one per event, either for people or,
+// with --json, one JSON object each for status bars and scripts
+use chrono::{Local, TimeZone};
+use serde_derive::Serialize;
+
+use crate::protobuf_sock::{Event, EventType};
+
+#[derive(Serialize)]
+struct WatchEvent<'a> {
+    event: &'static str,
+    // seconds since the epoch
+    time: Option<i64>,
+    id: Option<&'a str>,
+    title: Option<&'a str>,
+    until: Option<i64>,
+    applied: Option<bool>,
+    errors: &'a [String],
+}
+
+// None for events this pwalarmctl doesn't know about
+pub fn format(ev: &Event, json: bool) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let ty = match ev.type_.and_then(|t| t.enum_value().ok()) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let name = match ty {
+        EventType::Fired => "fired",
+        EventType::Snoozed => "snoozed",
+        EventType::Dismissed => "dismissed",
+        EventType::Added => "added",
+        EventType::Removed => "removed",
+        EventType::Modified => "modified",
+        EventType::Reloaded => "reloaded",
+        EventType::ShuttingDown => "shutting_down",
+    };
+    let al = ev.al.as_ref();
+    if json {
+        let we = WatchEvent {
+            event: name,
+            time: ev.time,
+            id: al.and_then(|a| a.id.as_deref()),
+            title: al.and_then(|a| a.title.as_deref()),
+            until: ev.until,
+            applied: ev.applied,
+            errors: &ev.errors,
+        };
+        return Ok(Some(serde_json::to_string(&we)?));
+    }
+    let what = if ev.applied == Some(false) {
+        "reload failed, keeping the previous config:".to_string()
+    } else {
+        name.replace('_', " ")
+    };
+    let mut line = format!("{} {}", stamp(ev.time, "%Y-%m-%d %H:%M:%S"), what);
+    if let Some(a) = al {
+        line += &format!(" {}", a.id.as_deref().unwrap_or("-"));
+        if let Some(ref t) = a.title {
+            line += &format!(" \"{}\"", t);
+        }
+    }
+    if ev.until.is_some() {
+        line += &format!(" until {}", stamp(ev.until, "%H:%M:%S"));
+    }
+    for e in &ev.errors {
+        line += &format!("\n  {}", e);
+    }
+    Ok(Some(line))
+}
+
+fn stamp(secs: Option<i64>, fmt: &str) -> String {
+    match secs.and_then(|s| Local.timestamp_opt(s, 0).single()) {
+        Some(t) => t.format(fmt).to_string(),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,230 @@
+// `pwalarmd --check`: reads a config the way the daemon would, without
+// starting it or opening audio, and lists every problem with its line
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use rodio::Decoder;
+use toml_edit::{ImDocument, Item, Table};
+
+use crate::{
+    alarm_name, alarm_problems, beprint, determine_next_run, run_dir, Alarm, Config, WEEKDAYS,
+};
+
+// Returns the exit code: 0 if nothing was found
+pub fn check(path: &str) -> i32 {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            beprint(&format!("unable to read {}: {}", path, e));
+            return 2;
+        }
+    };
+    let conf: Config = match toml::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => {
+            let line = e.span().map(|s| line_at(&text, s.start));
+            print_problem(path, line, e.message());
+            return 3;
+        }
+    };
+    // only used for line numbers, so a failure just loses those
+    let doc = ImDocument::parse(text.as_str()).ok();
+    let general = doc
+        .as_ref()
+        .and_then(|d| d.get("General"))
+        .and_then(Item::as_table);
+    let tables: Vec<&Table> = doc
+        .as_ref()
+        .and_then(|d| d.get("Alarm"))
+        .and_then(Item::as_array_of_tables)
+        .map(|a| a.iter().collect())
+        .unwrap_or_default();
+    let base = match run_dir(&conf) {
+        Ok(b) => b,
+        Err(e) => {
+            beprint(&format!("unable to find pwalarmd's directory: {}", e));
+            return 2;
+        }
+    };
+
+    // (byte offset, message)
+    let mut problems: Vec<(Option<usize>, String)> = vec![];
+    if let Some(ref s) = conf.general.sound {
+        if let Some(e) = sound_problem(s, &base) {
+            problems.push((
+                offset(general, "sound"),
+                format!("sound file \"{}\" {}", s, e),
+            ));
+        }
+    }
+    let alarms: Vec<&Alarm> = conf.alarms.iter().flatten().collect();
+    // first alarm seen with each id, and with each definition
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+    let mut seen: Vec<(Alarm, usize)> = vec![];
+    for (i, a) in alarms.iter().enumerate() {
+        let table = tables.get(i).copied();
+        let at = |key: &str| offset(table, key);
+        let name = alarm_name(a);
+        let mut add =
+            |off, msg: String| problems.push((off, format!("alarm \"{}\" {}", name, msg)));
+        // each bad day gets its own line, instead of check_alarm's first one
+        let mut bad_repeat = false;
+        for d in a.repeat.iter().flatten() {
+            if !WEEKDAYS.contains(&d.as_str()) {
+                bad_repeat = true;
+                add(
+                    repeat_offset(table, d).or(at("repeat")),
+                    format!(
+                        "has an unknown day \"{}\" in repeat (use {})",
+                        d,
+                        WEEKDAYS.join(", ")
+                    ),
+                );
+            }
+        }
+        let found = alarm_problems(a, &base);
+        // check_alarm's problems are the ones without a key of their own
+        let valid = found.iter().all(|(k, _)| !k.is_empty() && *k != "repeat");
+        for (key, msg) in found {
+            if key != "repeat" {
+                add(at(key), msg);
+            }
+        }
+        if let Some(ref s) = a.sound {
+            if base.join(s).is_file() {
+                if let Some(e) = sound_problem(s, &base) {
+                    add(
+                        at("sound"),
+                        format!("has a sound file that {}: \"{}\"", e, s),
+                    );
+                }
+            }
+        }
+        if let Some(t) = a.time {
+            if t.offset.is_some() {
+                add(
+                    at("time"),
+                    "has a UTC offset in time, which is ignored; times are local".to_string(),
+                );
+            }
+            if t.date.is_some() && a.rrule.is_none() && a.repeat.is_some() {
+                add(
+                    at("repeat"),
+                    "has a date, so it only rings once and repeat is ignored \
+                     (use an rrule to repeat from a date)"
+                        .to_string(),
+                );
+            }
+        }
+        if valid && !bad_repeat && a.enabled != Some(false) && determine_next_run(a).is_none() {
+            let (key, why) = if a.rrule.is_some() {
+                ("rrule", "its rrule has no occurrences left")
+            } else if a.time.is_some_and(|t| t.date.is_some()) {
+                ("time", "its date has already passed")
+            } else {
+                ("repeat", "repeat has no days")
+            };
+            add(at(key), format!("never rings: {}", why));
+        }
+        if let Some(ref id) = a.id {
+            match ids.get(id.as_str()) {
+                Some(j) => add(
+                    at("id"),
+                    format!(
+                        "has the same id \"{}\" as {}",
+                        id,
+                        describe(&text, tables.get(*j))
+                    ),
+                ),
+                None => {
+                    ids.insert(id, i);
+                }
+            }
+        }
+        let def = Alarm {
+            id: None,
+            ..(*a).clone()
+        };
+        match seen.iter().find(|(d, _)| *d == def) {
+            Some((_, j)) => add(
+                at(""),
+                format!("is a duplicate of {}", describe(&text, tables.get(*j))),
+            ),
+            None => seen.push((def, i)),
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", path);
+        return 0;
+    }
+    let mut problems: Vec<(Option<usize>, String)> = problems
+        .into_iter()
+        .map(|(off, msg)| (off.map(|o| line_at(&text, o)), msg))
+        .collect();
+    problems.sort_by_key(|(line, _)| *line);
+    for (line, msg) in &problems {
+        print_problem(path, *line, msg);
+    }
+    beprint(&format!(
+        "{} problem{} found",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" }
+    ));
+    3
+}
+
+fn print_problem(path: &str, line: Option<usize>, msg: &str) {
+    match line {
+        Some(l) => println!("{}:{}: {}", path, l, msg),
+        None => println!("{}: {}", path, msg),
+    }
+}
+
+fn line_at(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+// Where key is set in table, or the table itself for an empty key or
+// a key that isn't there
+fn offset(table: Option<&Table>, key: &str) -> Option<usize> {
+    let table = table?;
+    table
+        .get_key_value(key)
+        .and_then(|(k, _)| k.span())
+        .or_else(|| table.span())
+        .map(|s| s.start)
+}
+
+fn repeat_offset(table: Option<&Table>, day: &str) -> Option<usize> {
+    table?
+        .get("repeat")?
+        .as_array()?
+        .iter()
+        .find(|v| v.as_str() == Some(day))?
+        .span()
+        .map(|s| s.start)
+}
+
+// "the alarm on line N", for pointing at the first of two duplicates
+fn describe(text: &str, table: Option<&&Table>) -> String {
+    match table.and_then(|t| t.span()) {
+        Some(s) => format!("the alarm on line {}", line_at(text, s.start)),
+        None => "an earlier alarm".to_string(),
+    }
+}
+
+// Why a sound file that exists can't be played, if it can't
+fn sound_problem(path: &str, base: &Path) -> Option<String> {
+    let p = base.join(path);
+    if !p.is_file() {
+        return Some("doesn't exist".to_string());
+    }
+    let f = match File::open(&p) {
+        Ok(f) => f,
+        Err(e) => return Some(format!("can't be read ({})", e)),
+    };
+    match Decoder::new(BufReader::new(f)) {
+        Ok(_) => None,
+        Err(e) => Some(format!("can't be decoded ({})", e)),
+    }
+}
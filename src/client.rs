@@ -0,0 +1,194 @@
+// Connected clients, served from the main loop without ever blocking
+// it: requests are picked up as their bytes trickle in, and responses
+// wait in a buffer until the socket has room for them
+use std::{
+    cmp::min,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    time::Instant,
+};
+
+use crate::{frame, protobuf_sock::SocketResponse};
+
+// A client that stops reading its responses is dropped before they
+// take up more than this
+const MAX_PENDING: usize = 4 * frame::MAX_FRAME;
+// Nothing that's on its way to being a frame can be longer
+const MAX_INPUT: usize = frame::MAX_FRAME + frame::MAX_VARINT_LEN;
+// Read from one client per wakeup; a client that never stops writing
+// is picked up again on the next one, after the alarms have been seen to
+const MAX_READ: usize = 64 * 1024;
+const READ_SIZE: usize = 4096;
+
+pub struct Client {
+    stream: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // of the request being answered
+    pub rid: Option<u64>,
+    // when the last request came in, for dropping idle clients
+    pub active: Instant,
+    // sent Subscribe, so gets events and is never idle
+    pub subscribed: bool,
+    // sent something that isn't a frame
+    garbled: bool,
+    // to be dropped once the output has been written
+    closing: bool,
+    // the connection is broken, or the client stopped reading
+    closed: bool,
+}
+
+impl Client {
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            input: vec![],
+            output: vec![],
+            rid: None,
+            active: Instant::now(),
+            subscribed: false,
+            garbled: false,
+            closing: false,
+            closed: false,
+        })
+    }
+
+    // Reads what's arrived, up to MAX_READ, and returns the requests
+    // that are complete, in order
+    pub fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut buf = [0u8; READ_SIZE];
+        let mut read = 0;
+        while !self.closed && !self.closing && read < MAX_READ && self.input.len() < MAX_INPUT {
+            let room = min(READ_SIZE, MAX_INPUT - self.input.len());
+            match self.stream.read(&mut buf[..room]) {
+                // the client may still be reading what it asked for
+                Ok(0) => self.closing = true,
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    read += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => self.closed = true,
+            }
+        }
+        let mut reqs = vec![];
+        let mut used = 0;
+        while !self.garbled {
+            match frame::decode_frame(&self.input[used..]) {
+                Ok(Some((body, n))) => {
+                    reqs.push(body);
+                    used += n;
+                }
+                Ok(None) => break,
+                Err(_) => self.garbled = true,
+            }
+        }
+        self.input.drain(..used);
+        // decode_frame turns down lengths over MAX_FRAME, so this can only
+        // be a client that sends no frames at all; it isn't worth waiting for
+        if self.input.len() >= MAX_INPUT {
+            self.garbled = true;
+        }
+        if !reqs.is_empty() {
+            self.active = Instant::now();
+        }
+        reqs
+    }
+
+    // Whether the client sent something that can't be read as a frame;
+    // there's no telling where the next one would start
+    pub fn garbled(&self) -> bool {
+        self.garbled
+    }
+
+    // Queues resp, answering the current request
+    pub fn send(&mut self, mut resp: SocketResponse) {
+        if self.closed {
+            return;
+        }
+        resp.rid = self.rid;
+        if frame::write_frame(&mut self.output, &resp).is_err() || self.output.len() > MAX_PENDING {
+            self.closed = true;
+            return;
+        }
+        self.flush();
+    }
+
+    // Writes as much of the output as the socket takes
+    pub fn flush(&mut self) {
+        while !self.closed && !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // EPIPE included: the client is gone
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    pub fn is_open(&self) -> bool {
+        !(self.closed || self.closing && self.output.is_empty())
+    }
+}
+
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_bounded() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        tx.set_nonblocking(true).unwrap();
+        let mut c = Client::new(rx).unwrap();
+        // the start of the largest frame there can be, and far more
+        // of it than one wakeup reads
+        let mut sent = vec![0x80, 0x80, 0x40];
+        sent.resize(MAX_READ * 2, b'x');
+        let mut n = 0;
+        while n < sent.len() {
+            match tx.write(&sent[n..]) {
+                Ok(w) => n += w,
+                Err(_) => break,
+            }
+        }
+        assert!(n > MAX_READ);
+        assert!(c.receive().is_empty());
+        assert!(c.input.len() <= MAX_READ);
+        assert!(!c.garbled() && c.is_open());
+    }
+
+    #[test]
+    fn requests_are_split() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut c = Client::new(rx).unwrap();
+        tx.write_all(&[2, b'a', b'b', 0, 1]).unwrap();
+        assert_eq!(c.receive(), [b"ab".to_vec(), vec![]]);
+        tx.write_all(b"c").unwrap();
+        assert_eq!(c.receive(), [b"c".to_vec()]);
+        tx.write_all(&[0xff; 11]).unwrap();
+        assert!(c.receive().is_empty());
+        assert!(c.garbled());
+    }
+}
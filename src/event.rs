@@ -0,0 +1,273 @@
+// Thin wrappers around the Linux APIs the main loop sleeps on:
+// epoll to wait on several things at once, a timerfd for the next
+// alarm, inotify for the directories holding the config and
+// calendar files, and a signalfd for signals
+use std::{
+    ffi::{CString, OsStr, OsString},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const MAX_EVENTS: usize = 16;
+const EVENT_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+fn cvt(r: libc::c_int) -> io::Result<libc::c_int> {
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+pub struct Poller {
+    fd: OwnedFd,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // token is handed back by wait when fd becomes readable
+    pub fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        cvt(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut ev) })?;
+        Ok(())
+    }
+
+    // Whether wait should also hand back token once fd has room to write,
+    // for sockets with output waiting
+    pub fn set_writable(&self, fd: RawFd, token: u64, writable: bool) -> io::Result<()> {
+        let mut events = libc::EPOLLIN as u32;
+        if writable {
+            events |= libc::EPOLLOUT as u32;
+        }
+        let mut ev = libc::epoll_event { events, u64: token };
+        cvt(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), libc::EPOLL_CTL_MOD, fd, &mut ev) })?;
+        Ok(())
+    }
+
+    // Stops fd waking wait at all, until set_writable is next called for it
+    pub fn pause(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut ev = libc::epoll_event {
+            events: 0,
+            u64: token,
+        };
+        cvt(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), libc::EPOLL_CTL_MOD, fd, &mut ev) })?;
+        Ok(())
+    }
+
+    // Sleeps until something is readable or the timeout runs out,
+    // and returns the tokens of whatever is ready
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<u64>> {
+        let mut evs = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // round up, so we don't wake just short of a deadline and spin
+        let ms = timeout.map_or(-1, |d| {
+            ((d.as_nanos() + 999_999) / 1_000_000).min(i32::MAX as u128) as i32
+        });
+        let n = match cvt(unsafe {
+            libc::epoll_wait(self.fd.as_raw_fd(), evs.as_mut_ptr(), MAX_EVENTS as i32, ms)
+        }) {
+            Ok(n) => n as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(evs[..n].iter().map(|e| e.u64).collect())
+    }
+}
+
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+impl Timer {
+    pub fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe {
+            libc::timerfd_create(libc::CLOCK_REALTIME, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC)
+        })?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // Fires at the wall-clock time `at` (right away if it has passed),
+    // or never if it's None. The timer also fires when the system clock
+    // is set, so a changed clock never leaves it waiting for the wrong time.
+    pub fn arm_at(&self, at: Option<SystemTime>) -> io::Result<()> {
+        let zero = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let value = match at {
+            Some(t) => {
+                // an all-zero time would disarm the timer instead
+                let d = t
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .max(Duration::from_nanos(1));
+                libc::timespec {
+                    tv_sec: d.as_secs() as libc::time_t,
+                    tv_nsec: d.subsec_nanos() as libc::c_long,
+                }
+            }
+            None => zero,
+        };
+        let spec = libc::itimerspec {
+            it_interval: zero,
+            it_value: value,
+        };
+        cvt(unsafe {
+            libc::timerfd_settime(
+                self.fd.as_raw_fd(),
+                libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET,
+                &spec,
+                std::ptr::null_mut(),
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn drain(&self) {
+        let mut buf = [0u8; 8];
+        // fails with ECANCELED after a clock change, which is fine:
+        // the caller rearms the timer either way
+        unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+pub struct Watcher {
+    fd: OwnedFd,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = cvt(unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) })?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // Watches a directory for files in it being written, created,
+    // renamed or deleted. Watching one that's already watched is harmless.
+    pub fn watch(&self, dir: &Path) -> io::Result<()> {
+        let cpath = CString::new(dir.as_os_str().as_bytes())?;
+        cvt(unsafe {
+            libc::inotify_add_watch(
+                self.fd.as_raw_fd(),
+                cpath.as_ptr(),
+                libc::IN_MODIFY
+                    | libc::IN_CLOSE_WRITE
+                    | libc::IN_CREATE
+                    | libc::IN_DELETE
+                    | libc::IN_MOVED_FROM
+                    | libc::IN_MOVED_TO,
+            )
+        })?;
+        Ok(())
+    }
+
+    // The names of the files that changed, in any watched directory
+    pub fn drain(&self) -> Vec<OsString> {
+        let mut names = vec![];
+        // aligned for inotify_event, and big enough for at least one
+        // event with the longest possible name
+        let mut buf = [0u64; 4096 / 8];
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    std::mem::size_of_val(&buf),
+                )
+            };
+            if n <= 0 {
+                return names;
+            }
+            let bytes =
+                unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), n as usize) };
+            let mut off = 0;
+            while off + EVENT_SIZE <= bytes.len() {
+                let ev: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(bytes[off..].as_ptr().cast()) };
+                let name = &bytes[off + EVENT_SIZE..off + EVENT_SIZE + ev.len as usize];
+                // the name is padded out with NULs
+                let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                if end > 0 {
+                    names.push(OsStr::from_bytes(&name[..end]).to_os_string());
+                }
+                off += EVENT_SIZE + ev.len as usize;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+pub struct Signals {
+    fd: OwnedFd,
+}
+
+impl Signals {
+    // Blocks sigs, so they only arrive through the fd. Threads inherit
+    // the mask of the thread that starts them, so this has to happen
+    // before any are started (rodio's output stream starts one).
+    pub fn new(sigs: &[libc::c_int]) -> io::Result<Self> {
+        let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for s in sigs {
+            cvt(unsafe { libc::sigaddset(&mut set, *s) })?;
+        }
+        let r = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if r != 0 {
+            return Err(io::Error::from_raw_os_error(r));
+        }
+        let fd = cvt(unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) })?;
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // The signals received since the last call, in order
+    pub fn drain(&self) -> Vec<libc::c_int> {
+        let mut sigs = vec![];
+        let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+        while unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                (&mut info as *mut libc::signalfd_siginfo).cast(),
+                std::mem::size_of::<libc::signalfd_siginfo>(),
+            )
+        } > 0
+        {
+            sigs.push(info.ssi_signo as libc::c_int);
+        }
+        sigs
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,188 @@
+// Framing for the control socket: every message is a varint length
+// followed by that many bytes of protobuf, so neither side has to
+// guess where a message ends. Shared between pwalarmd and pwalarmctl.
+use std::io::{self, Read, Write};
+
+use protobuf::Message;
+
+// Big enough for thousands of alarms; anything larger is taken
+// to be garbage rather than allocated
+pub const MAX_FRAME: usize = 1 << 20;
+
+// A u64 takes at most 10 bytes as a varint
+pub const MAX_VARINT_LEN: usize = 10;
+
+pub fn write_frame<M: Message>(w: &mut impl Write, msg: &M) -> io::Result<()> {
+    if msg.compute_size() as usize > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message is larger than {} bytes", MAX_FRAME),
+        ));
+    }
+    // encoded up front: protobuf's own writer panics if the peer
+    // hangs up before it's flushed
+    w.write_all(&msg.write_length_delimited_to_bytes()?)?;
+    w.flush()
+}
+
+// The body of the next frame, waiting for all of it to arrive.
+// A clean end of stream before the frame starts is UnexpectedEof.
+// Used by pwalarmctl; pwalarmd can't afford to wait.
+#[allow(dead_code)]
+pub fn read_frame(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len: u64 = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let mut b = [0u8];
+        r.read_exact(&mut b)?;
+        len |= u64::from(b[0] & 0x7f) << (7 * i);
+        if b[0] & 0x80 == 0 {
+            let mut body = vec![0u8; check_len(len)?];
+            r.read_exact(&mut body)?;
+            return Ok(body);
+        }
+    }
+    Err(bad_varint())
+}
+
+// For readers that can't wait: the body of the frame at the start of
+// buf and the number of bytes it takes up there, or None if the rest
+// of it hasn't arrived yet. Used by pwalarmd, which mustn't block.
+#[allow(dead_code)]
+pub fn decode_frame(buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+    let mut len: u64 = 0;
+    for (i, b) in buf.iter().take(MAX_VARINT_LEN).enumerate() {
+        len |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            let start = i + 1;
+            let end = start + check_len(len)?;
+            if buf.len() < end {
+                return Ok(None);
+            }
+            return Ok(Some((buf[start..end].to_vec(), end)));
+        }
+    }
+    if buf.len() >= MAX_VARINT_LEN {
+        return Err(bad_varint());
+    }
+    Ok(None)
+}
+
+fn check_len(len: u64) -> io::Result<usize> {
+    if len > MAX_FRAME as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is larger than {}", len, MAX_FRAME),
+        ));
+    }
+    Ok(len as usize)
+}
+
+fn bad_varint() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "frame length is not a valid varint",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf_sock::{RemoveAlarm, SocketRequest};
+
+    fn request(id: &str) -> Vec<u8> {
+        let mut ra = RemoveAlarm::new();
+        ra.id = Some(id.to_string());
+        let mut sr = SocketRequest::new();
+        sr.set_ra(ra);
+        let mut buf = vec![];
+        write_frame(&mut buf, &sr).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = request("morning");
+        let body = read_frame(&mut buf.as_slice()).unwrap();
+        let sr = SocketRequest::parse_from_bytes(&body).unwrap();
+        assert_eq!(sr.ra().id(), "morning");
+    }
+
+    #[test]
+    fn read_at_end_of_stream() {
+        let err = read_frame(&mut [].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn decode_partial() {
+        let buf = request("morning");
+        for i in 0..buf.len() {
+            assert!(decode_frame(&buf[..i]).unwrap().is_none(), "{}", i);
+        }
+        let (body, n) = decode_frame(&buf).unwrap().unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(body, buf[1..]);
+    }
+
+    #[test]
+    fn decode_one_at_a_time() {
+        let mut buf = request("a");
+        buf.extend(request("bb"));
+        let (_, n) = decode_frame(&buf).unwrap().unwrap();
+        let (body, m) = decode_frame(&buf[n..]).unwrap().unwrap();
+        assert_eq!(n + m, buf.len());
+        let sr = SocketRequest::parse_from_bytes(&body).unwrap();
+        assert_eq!(sr.ra().id(), "bb");
+    }
+
+    #[test]
+    fn oversize() {
+        // MAX_FRAME + 1 as a varint
+        let len = MAX_FRAME as u64 + 1;
+        let buf = [
+            (len & 0x7f) as u8 | 0x80,
+            (len >> 7 & 0x7f) as u8 | 0x80,
+            (len >> 14) as u8,
+        ];
+        assert_eq!(
+            decode_frame(&buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read_frame(&mut buf.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn bad_varint() {
+        assert!(decode_frame(&[0xff; 9]).unwrap().is_none());
+        assert_eq!(
+            decode_frame(&[0xff; 10]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read_frame(&mut [0xff; 11].as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn write_oversize() {
+        let mut ra = RemoveAlarm::new();
+        ra.id = Some("x".repeat(MAX_FRAME));
+        let mut sr = SocketRequest::new();
+        sr.set_ra(ra);
+        let err = write_frame(&mut vec![], &sr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn write_to_closed_socket() {
+        let (mut tx, rx) = std::os::unix::net::UnixStream::pair().unwrap();
+        drop(rx);
+        let mut sr = SocketRequest::new();
+        sr.set_ra(RemoveAlarm::new());
+        assert!(write_frame(&mut tx, &sr).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+// Reads the alarms out of an iCalendar (RFC 5545) file. Every VALARM
+// in a VEVENT becomes one alarm, firing at the event's start shifted by
+// the TRIGGER offset; events without a VALARM fire at their start.
+// Shared between pwalarmd and pwalarmctl.
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+pub struct IcsAlarm {
+    // the event's UID, with #n appended for its nth VALARM after the first
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    // when the first occurrence fires
+    pub at: NaiveDateTime,
+    // the event's RRULE, with its days shifted to follow the alarm when
+    // the trigger moves it onto another day
+    pub rrule: Option<String>,
+}
+
+#[derive(Default)]
+struct Event {
+    uid: Option<String>,
+    summary: Option<String>,
+    description: Option<String>,
+    start: Option<NaiveDateTime>,
+    end: Option<NaiveDateTime>,
+    duration: Option<Duration>,
+    rrule: Option<String>,
+    // (offset, relative to the end instead of the start) or an absolute time
+    triggers: Vec<Result<(Duration, bool), NaiveDateTime>>,
+}
+
+pub fn parse_ics(text: &str) -> Result<Vec<IcsAlarm>, String> {
+    let mut alarms = vec![];
+    let mut event: Option<Event> = None;
+    let mut in_alarm = false;
+    for (name, params, value) in unfold(text).iter().map(|l| split_line(l)) {
+        match (name.as_str(), value.as_str()) {
+            ("BEGIN", "VEVENT") => event = Some(Event::default()),
+            ("BEGIN", "VALARM") => in_alarm = true,
+            ("END", "VALARM") => in_alarm = false,
+            ("END", "VEVENT") => {
+                if let Some(ev) = event.take() {
+                    alarms.extend(event_alarms(ev)?);
+                }
+            }
+            _ => {
+                let ev = match event {
+                    Some(ref mut ev) => ev,
+                    None => continue,
+                };
+                if in_alarm {
+                    if name == "TRIGGER" {
+                        ev.triggers
+                            .push(if params.contains(&"VALUE=DATE-TIME".to_string()) {
+                                Err(parse_datetime(&value, &params)?)
+                            } else {
+                                Ok((
+                                    parse_duration(&value)?,
+                                    params.contains(&"RELATED=END".to_string()),
+                                ))
+                            });
+                    }
+                    continue;
+                }
+                match name.as_str() {
+                    "UID" => ev.uid = Some(value),
+                    "SUMMARY" => ev.summary = Some(unescape(&value)),
+                    "DESCRIPTION" => ev.description = Some(unescape(&value)),
+                    "DTSTART" => ev.start = Some(parse_datetime(&value, &params)?),
+                    "DTEND" => ev.end = Some(parse_datetime(&value, &params)?),
+                    "DURATION" => ev.duration = Some(parse_duration(&value)?),
+                    "RRULE" => ev.rrule = Some(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(alarms)
+}
+
+fn event_alarms(ev: Event) -> Result<Vec<IcsAlarm>, String> {
+    let start = ev.start.ok_or_else(|| {
+        format!(
+            "event \"{}\" has no DTSTART",
+            ev.summary.as_deref().unwrap_or("(untitled)")
+        )
+    })?;
+    let too_far = || {
+        format!(
+            "event \"{}\" has an alarm too far from its start",
+            ev.summary.as_deref().unwrap_or("(untitled)")
+        )
+    };
+    let end = match (ev.end, ev.duration) {
+        (Some(e), _) => e,
+        (None, Some(d)) => start.checked_add_signed(d).ok_or_else(too_far)?,
+        (None, None) => start,
+    };
+    let triggers = if ev.triggers.is_empty() {
+        vec![Ok((Duration::zero(), false))]
+    } else {
+        ev.triggers
+    };
+    triggers
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let at = match t {
+                Ok((d, true)) => end.checked_add_signed(d).ok_or_else(too_far)?,
+                Ok((d, false)) => start.checked_add_signed(d).ok_or_else(too_far)?,
+                Err(at) => at,
+            };
+            Ok(IcsAlarm {
+                id: ev.uid.as_ref().map(|u| {
+                    if i == 0 {
+                        u.clone()
+                    } else {
+                        format!("{}#{}", u, i + 1)
+                    }
+                }),
+                title: ev.summary.clone(),
+                description: ev.description.clone(),
+                at,
+                rrule: match t {
+                    Ok(_) => ev.rrule.as_deref().map(|r| shift_rrule(r, start, at)),
+                    Err(_) => ev.rrule.clone(),
+                },
+            })
+        })
+        .collect()
+}
+
+const WEEKDAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+
+// Moves a rule for occurrences at `from` to one for occurrences at `to`:
+// a Monday 00:10 event with a -PT15M alarm rings on Sundays, and its
+// UNTIL moves along. Ordinal weekdays and month days near the end of
+// the month can't shift exactly, and are moved by the same number of days.
+fn shift_rrule(rule: &str, from: NaiveDateTime, to: NaiveDateTime) -> String {
+    let days = (to.date() - from.date()).num_days();
+    rule.split(';')
+        .map(|part| {
+            let (k, v) = match part.split_once('=') {
+                Some(kv) => kv,
+                None => return part.to_string(),
+            };
+            let v = match k.to_ascii_uppercase().as_str() {
+                "BYDAY" if days != 0 => v
+                    .split(',')
+                    .map(|d| shift_weekday(d, days))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                "BYMONTHDAY" if days != 0 => v
+                    .split(',')
+                    .map(|d| match d.parse::<i64>() {
+                        // the day before the 1st is the last (-1),
+                        // and the one after the last is the 1st
+                        Ok(n) if n > 0 && n + days < 1 => (n + days - 1).to_string(),
+                        Ok(n) if n < 0 && n + days > -1 => (n + days + 1).to_string(),
+                        Ok(n) if n > 0 && n + days > 31 => (n + days - 31).to_string(),
+                        Ok(n) if n < 0 && n + days < -31 => (n + days + 31).to_string(),
+                        Ok(n) => (n + days).to_string(),
+                        Err(_) => d.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                "UNTIL" => shift_until(v, to - from, days).unwrap_or_else(|| v.to_string()),
+                _ => v.to_string(),
+            };
+            format!("{}={}", k, v)
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+// [+-]nWD, keeping the ordinal; anything unreadable is left as it is
+fn shift_weekday(d: &str, days: i64) -> String {
+    let at = match d.char_indices().rev().nth(1) {
+        Some((at, _)) => at,
+        None => return d.to_string(),
+    };
+    let (n, w) = d.split_at(at);
+    match WEEKDAYS.iter().position(|x| x.eq_ignore_ascii_case(w)) {
+        Some(i) => format!(
+            "{}{}",
+            n,
+            WEEKDAYS[(i as i64 + days).rem_euclid(7) as usize]
+        ),
+        None => d.to_string(),
+    }
+}
+
+// UNTIL is a date, a local date-time or a UTC one ending in Z
+fn shift_until(v: &str, offset: Duration, days: i64) -> Option<String> {
+    if let Ok(d) = NaiveDate::parse_from_str(v, "%Y%m%d") {
+        return d
+            .checked_add_signed(Duration::days(days))
+            .map(|d| d.format("%Y%m%d").to_string());
+    }
+    let (u, z) = match v.strip_suffix('Z') {
+        Some(u) => (u, "Z"),
+        None => (v, ""),
+    };
+    NaiveDateTime::parse_from_str(u, "%Y%m%dT%H%M%S")
+        .ok()?
+        .checked_add_signed(offset)
+        .map(|dt| format!("{}{}", dt.format("%Y%m%dT%H%M%S"), z))
+}
+
+// Long lines are folded by starting the continuation with whitespace
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for l in text.lines() {
+        match (l.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(l.trim_end_matches('\r').to_string()),
+        }
+    }
+    lines
+}
+
+// NAME;PARAM=x;PARAM=y:VALUE, with the name and parameters uppercased
+fn split_line(line: &str) -> (String, Vec<String>, String) {
+    // parameter values can be quoted, and quotes can contain colons
+    let mut quoted = false;
+    let colon = line.find(|c| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == ':' && !quoted
+    });
+    let (head, value) = match colon {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+    };
+    let mut parts = head.split(';');
+    let name = parts.next().unwrap_or("").to_ascii_uppercase();
+    let params = parts.map(|p| p.to_ascii_uppercase()).collect();
+    (name, params, value.to_string())
+}
+
+// UTC times (ending in Z) are converted to local time. Times with a
+// TZID are taken as local time, since there's no timezone database here.
+// All-day dates start at midnight.
+fn parse_datetime(value: &str, params: &[String]) -> Result<NaiveDateTime, String> {
+    let err = || format!("invalid date-time '{}'", value);
+    if params.contains(&"VALUE=DATE".to_string()) || value.len() == 8 {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map_err(|_| err())?
+            .and_hms_opt(0, 0, 0)
+            .ok_or_else(err);
+    }
+    if let Some(u) = value.strip_suffix('Z') {
+        let dt = NaiveDateTime::parse_from_str(u, "%Y%m%dT%H%M%S").map_err(|_| err())?;
+        return Ok(Local.from_utc_datetime(&dt).naive_local());
+    }
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").map_err(|_| err())
+}
+
+// [+-]P[nW][nD][T[nH][nM][nS]], e.g. -PT15M or P1DT12H
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let err = || format!("invalid duration '{}'", value);
+    let (neg, rest) = match value.strip_prefix('-') {
+        Some(r) => (true, r),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(err)?;
+    let mut secs: i64 = 0;
+    let mut num = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        if c == 'T' {
+            in_time = true;
+            continue;
+        }
+        let n: i64 = num.parse().map_err(|_| err())?;
+        num.clear();
+        let unit = match (c, in_time) {
+            ('W', false) => 604800,
+            ('D', false) => 86400,
+            ('H', true) => 3600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return Err(err()),
+        };
+        secs = n
+            .checked_mul(unit)
+            .and_then(|d| secs.checked_add(d))
+            .ok_or_else(err)?;
+    }
+    // P and PT on their own say nothing
+    if !num.is_empty() || rest.is_empty() || rest.ends_with('T') {
+        return Err(err());
+    }
+    Duration::try_seconds(if neg { -secs } else { secs }).ok_or_else(err)
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(e) => out.push(e),
+            None => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn ics(events: &str) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{}END:VCALENDAR\r\n",
+            events
+        )
+    }
+
+    #[test]
+    fn unfold_lines() {
+        assert_eq!(
+            unfold("SUMMARY:Lo\r\n ng\r\n\tER\r\nUID:x\r\n"),
+            ["SUMMARY:LongER", "UID:x"]
+        );
+        assert_eq!(unfold("A:1\nB:2"), ["A:1", "B:2"]);
+        // nothing to continue
+        assert_eq!(unfold(" A:1"), [" A:1"]);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("-PT15M"), Ok(Duration::minutes(-15)));
+        assert_eq!(parse_duration("P1DT12H"), Ok(Duration::hours(36)));
+        assert_eq!(parse_duration("+P2W"), Ok(Duration::weeks(2)));
+        assert_eq!(parse_duration("PT1H2M3S"), Ok(Duration::seconds(3723)));
+        assert_eq!(parse_duration("P0D"), Ok(Duration::zero()));
+        for d in [
+            "", "P", "PT", "P1DT", "15M", "P1H", "PT1D", "PT5", "P-1D", "PTM",
+        ] {
+            assert!(parse_duration(d).is_err(), "{}", d);
+        }
+    }
+
+    #[test]
+    fn huge_durations() {
+        // overflows the multiply, then Duration, then the date
+        assert!(parse_duration("-P999999999999999D").is_err());
+        assert!(parse_duration("-P9999999999999D").is_err());
+        assert!(parse_duration("P99999999999999999999S").is_err());
+        // fine as a duration, but not once added to the start
+        assert!(parse_ics(&ics("BEGIN:VEVENT\r\n\
+             DTSTART:20240101T000000\r\n\
+             BEGIN:VALARM\r\n\
+             TRIGGER:-P99999999D\r\n\
+             END:VALARM\r\n\
+             END:VEVENT\r\n"))
+        .is_err());
+        assert!(parse_ics(&ics("BEGIN:VEVENT\r\n\
+             DTSTART:20240101T000000\r\n\
+             DURATION:P99999999D\r\n\
+             END:VEVENT\r\n"))
+        .is_err());
+    }
+
+    #[test]
+    fn event_without_alarm() {
+        let a = parse_ics(&ics("BEGIN:VEVENT\r\n\
+             UID:u1\r\n\
+             SUMMARY:Stand-up\\, daily\r\n\
+             DTSTART:20240102T093000\r\n\
+             RRULE:FREQ=DAILY\r\n\
+             END:VEVENT\r\n"))
+        .unwrap();
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0].id.as_deref(), Some("u1"));
+        assert_eq!(a[0].title.as_deref(), Some("Stand-up, daily"));
+        assert_eq!(a[0].at, dt("2024-01-02 09:30"));
+        assert_eq!(a[0].rrule.as_deref(), Some("FREQ=DAILY"));
+    }
+
+    #[test]
+    fn triggers() {
+        let a = parse_ics(&ics("BEGIN:VEVENT\r\n\
+             UID:u2\r\n\
+             DTSTART;TZID=Europe/Berlin:20240102T100000\r\n\
+             DURATION:PT1H\r\n\
+             BEGIN:VALARM\r\n\
+             TRIGGER:-PT15M\r\n\
+             END:VALARM\r\n\
+             BEGIN:VALARM\r\n\
+             TRIGGER;RELATED=END:PT0S\r\n\
+             END:VALARM\r\n\
+             BEGIN:VALARM\r\n\
+             TRIGGER;VALUE=DATE-TIME:20240101T200000\r\n\
+             END:VALARM\r\n\
+             END:VEVENT\r\n"))
+        .unwrap();
+        let ids: Vec<_> = a.iter().map(|x| x.id.as_deref().unwrap()).collect();
+        assert_eq!(ids, ["u2", "u2#2", "u2#3"]);
+        assert_eq!(a[0].at, dt("2024-01-02 09:45"));
+        assert_eq!(a[1].at, dt("2024-01-02 11:00"));
+        assert_eq!(a[2].at, dt("2024-01-01 20:00"));
+    }
+
+    #[test]
+    fn rule_follows_the_alarm() {
+        // 2024-01-01 is a Monday
+        let a = parse_ics(&ics("BEGIN:VEVENT\r\n\
+             DTSTART:20240101T001000\r\n\
+             RRULE:FREQ=WEEKLY;BYDAY=MO,-1FR;UNTIL=20240129T001000\r\n\
+             BEGIN:VALARM\r\n\
+             TRIGGER:-PT15M\r\n\
+             END:VALARM\r\n\
+             BEGIN:VALARM\r\n\
+             TRIGGER:-PT5M\r\n\
+             END:VALARM\r\n\
+             END:VEVENT\r\n"))
+        .unwrap();
+        assert_eq!(a[0].at, dt("2023-12-31 23:55"));
+        assert_eq!(
+            a[0].rrule.as_deref(),
+            Some("FREQ=WEEKLY;BYDAY=SU,-1TH;UNTIL=20240128T235500")
+        );
+        // same day, so only UNTIL moves
+        assert_eq!(
+            a[1].rrule.as_deref(),
+            Some("FREQ=WEEKLY;BYDAY=MO,-1FR;UNTIL=20240129T000500")
+        );
+    }
+
+    #[test]
+    fn month_days_wrap() {
+        let start = dt("2024-01-01 00:10");
+        let r = |s, mins| shift_rrule(s, start, start + Duration::minutes(mins));
+        assert_eq!(
+            r("FREQ=MONTHLY;BYMONTHDAY=1,15,-1", -15),
+            "FREQ=MONTHLY;BYMONTHDAY=-1,14,-2"
+        );
+        assert_eq!(
+            r("FREQ=MONTHLY;BYMONTHDAY=-1,30,31;UNTIL=20240301", 1440),
+            "FREQ=MONTHLY;BYMONTHDAY=1,31,1;UNTIL=20240302"
+        );
+    }
+
+    #[test]
+    fn all_day_event() {
+        let a = parse_ics(&ics("BEGIN:VEVENT\r\n\
+             DTSTART;VALUE=DATE:20240301\r\n\
+             END:VEVENT\r\n"))
+        .unwrap();
+        assert_eq!(a[0].at, dt("2024-03-01 00:00"));
+        assert!(a[0].id.is_none());
+    }
+
+    #[test]
+    fn bad_events() {
+        assert!(parse_ics(&ics("BEGIN:VEVENT\r\nSUMMARY:x\r\nEND:VEVENT\r\n")).is_err());
+        assert!(parse_ics(&ics("BEGIN:VEVENT\r\nDTSTART:2024\r\nEND:VEVENT\r\n")).is_err());
+        assert!(parse_ics(&ics(
+            "BEGIN:VEVENT\r\nDTSTART:20240101T000000\r\nBEGIN:VALARM\r\nTRIGGER:soon\r\n\
+             END:VALARM\r\nEND:VEVENT\r\n"
+        ))
+        .is_err());
+        // no events, no alarms
+        assert!(parse_ics("").unwrap().is_empty());
+    }
+}
@@ -2,32 +2,78 @@
 // The only thing that should actually crash is config issues
 // (or extraordinary circumstances, like unrepresentable times)
 // Otherwise, fall back to a sane default + log
-// TODO: pwalarmctl
-// send requests (new, modify, remove); save to write to .toml
-//   (if and only if user has write access)
 use std::{
-    cmp::{min, Ordering},
-    collections::VecDeque,
+    cmp::{max, min, Ordering},
+    collections::{HashMap, VecDeque},
+    ffi::CString,
     fs::File,
-    io::{BufReader, Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
-    path::Path,
-    time::Duration,
+    io::{BufReader, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use chrono::{Datelike, Local, NaiveDate, NaiveTime, Weekday};
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Weekday};
 use colored::Colorize;
+use cron::Schedule;
 use daemonize::Daemonize;
 use notify_rust::Notification;
 use protobuf::Message;
-use rodio::{source::SamplesConverter, Decoder, OutputStream, Source};
+use rodio::{source::SamplesConverter, Decoder, OutputStream, OutputStreamHandle, Sink, Source};
 use serde_derive::{Deserialize, Serialize};
 use toml::value::Datetime;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, Value};
 
+mod check;
+mod client;
+mod event;
+mod frame;
+mod ical;
+mod pidfile;
+#[allow(renamed_and_removed_lints)]
 mod protobuf_sock;
-use protobuf_sock::{socket_request, AlarmInfo, ErrorReason, GeneralInfoType};
+mod rrule;
+mod systemd;
+use protobuf_sock::{
+    socket_request, AlarmInfo, ErrorReason, EventType, GeneralInfoType, TimerInfo,
+};
+
+// 9 minutes, the traditional snooze length
+const DEFAULT_SNOOZE: u64 = 540;
+const DEFAULT_MAX_RING: u64 = 300;
+// The day names repeat takes
+const WEEKDAYS: [&str; 7] = ["Mo", "Tu", "We", "Th", "Fr", "Sa", "Su"];
+// What woke the main loop up, see event.rs
+const TOKEN_SOCKET: u64 = 0;
+const TOKEN_TIMER: u64 = 1;
+const TOKEN_WATCH: u64 = 2;
+const TOKEN_SIGNAL: u64 = 3;
+// and each client gets its own, from here up
+const TOKEN_CLIENT: u64 = 4;
+// How long --replace waits for the old pwalarmd to exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long the watched files have to stay untouched before a reload
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+// How long a connected client can go without sending a request
+// before it's dropped
+const CLIENT_IDLE: Duration = Duration::from_secs(30);
+// Connections past this many are closed as soon as they're accepted
+const MAX_CLIENTS: usize = 64;
+// How long to stop accepting connections after accept fails (say, out
+// of file descriptors), rather than spinning on the listening socket
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+// The most occurrences one SkipNext can skip; each one is worked out
+// in turn, and the skips only last until pwalarmd restarts anyway
+const MAX_SKIP: u32 = 1000;
 
-const BUFFER_READ: usize = 16384;
+type SoundSource = SamplesConverter<Decoder<BufReader<File>>, f32>;
 
 #[derive(Serialize, Deserialize)]
 struct Config {
@@ -35,67 +81,134 @@ struct Config {
     general: GeneralConfig,
     #[serde(rename = "Alarm")]
     alarms: Option<Vec<Alarm>>,
+    #[serde(rename = "Calendar")]
+    calendars: Option<Vec<CalendarConfig>>,
+}
+
+enum ConfigError {
+    Read(std::io::Error),
+    Toml(toml::de::Error),
+    // everything validate_config found wrong
+    Invalid(Vec<String>),
+}
+
+impl ConfigError {
+    fn messages(&self) -> Vec<String> {
+        match self {
+            ConfigError::Read(e) => vec![format!("unable to read config file: {}", e)],
+            ConfigError::Toml(e) => vec![format!("invalid TOML in config file: {}", e)],
+            ConfigError::Invalid(v) => v.clone(),
+        }
+    }
+}
+
+// The outcome of the last config reload, for FetchLastReloadStatus
+struct ReloadStatus {
+    time: SystemTime,
+    // empty if the new config was applied
+    errors: Vec<String>,
+}
+
+// An iCalendar file to read alarms from, see ical.rs
+#[derive(Serialize, Deserialize)]
+struct CalendarConfig {
+    path: String,
 }
 
 #[derive(Serialize, Deserialize)]
 struct GeneralConfig {
     sound: Option<String>,
+    // poll, tpfc and tsfc are left over from the polling loop, and unused
     poll: Option<u64>,
     notify: bool,
     custom_app_name: Option<String>,
     daemon: Option<bool>,
     tpfc: Option<u16>,
     tsfc: Option<u16>,
+    // seconds
+    snooze: Option<u64>,
+    // seconds an alarm may ring before it is stopped; 0 = no limit
+    max_ring: Option<u64>,
+    // write runtime changes back to the config file as they happen
+    autosave: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, PartialOrd, Ord, Debug)]
 struct Alarm {
+    // Generated when missing, see assign_ids
+    id: Option<String>,
     title: Option<String>,
     description: Option<String>,
-    time: Datetime,
+    // A time with a date (2026-12-24T07:00:00) is a one-shot alarm;
+    // it rings once on that date and then retires itself
+    time: Option<Datetime>,
     // ["Mo", "We", ...]
     repeat: Option<Vec<String>>,
-    // TODO: allow Volume control, sets system volume (avoids mute)
+    // cron expression, used instead of time and repeat:
+    // "sec min hour day-of-month month day-of-week [year]"
+    schedule: Option<String>,
+    // RFC 5545 recurrence rule, used instead of repeat;
+    // the date in time is the rule's start (DTSTART)
+    rrule: Option<String>,
     sound: Option<String>,
     icon: Option<String>,
+    // seconds, overrides the global snooze length
+    snooze: Option<u64>,
+    // seconds, overrides the global maximum ring time
+    max_ring: Option<u64>,
+    // 0-100, relative to the sound file; the system mixer is left alone
+    volume: Option<u8>,
+    // fade in from silence to full volume over this many seconds
+    ramp_seconds: Option<u64>,
+    // false keeps the alarm in the config without ever ringing it
+    enabled: Option<bool>,
 }
 
 #[derive(PartialEq, Eq)]
 struct LocalAlarm {
-    next_run_date: NaiveDate,
+    next_run: NaiveDateTime,
     alarm: Alarm,
 }
 impl PartialOrd for LocalAlarm {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.next_run_date > other.next_run_date {
-            Some(Ordering::Greater)
-        } else if self.next_run_date < other.next_run_date {
-            Some(Ordering::Less)
-        } else {
-            let t1 = match self.alarm.time.time {
-                Some(v) => v,
-                None => return None,
-            };
-            let t2 = match other.alarm.time.time {
-                Some(v) => v,
-                None => return None,
-            };
-            Some(NaiveTime::cmp(
-                match &NaiveTime::from_hms_opt(t1.hour.into(), t1.minute.into(), t1.second.into()) {
-                    Some(v) => v,
-                    None => return None,
-                },
-                match &NaiveTime::from_hms_opt(t2.hour.into(), t2.minute.into(), t2.second.into()) {
-                    Some(v) => v,
-                    None => return None,
-                },
-            ))
-        }
+        Some(self.cmp(other))
     }
 }
 impl Ord for LocalAlarm {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.next_run
+            .cmp(&other.next_run)
+            .then_with(|| self.alarm.cmp(&other.alarm))
+    }
+}
+
+// Snoozed occurrences are kept apart from the alarm ring,
+// so snoozing never moves the alarm's regular schedule
+struct SnoozedAlarm {
+    until: NaiveDateTime,
+    alarm: Alarm,
+}
+
+// A countdown started with NewTimer. It rings through the same path
+// as alarms, so it carries an Alarm built from the request.
+struct Timer {
+    until: NaiveDateTime,
+    // seconds, as requested
+    duration: u64,
+    alarm: Alarm,
+}
+
+// An alarm whose sound is looping until it is dismissed,
+// snoozed, or runs past its deadline
+struct RingingAlarm {
+    alarm: Alarm,
+    sink: Sink,
+    deadline: Option<Instant>,
+}
+// Removing an alarm from the ringing list silences it
+impl Drop for RingingAlarm {
+    fn drop(&mut self) {
+        self.sink.stop();
     }
 }
 
@@ -104,50 +217,106 @@ impl Ord for LocalAlarm {
 // One in /etc/pwalarmd.toml
 // One in /etc/xdg/pwalarmd/pwalarmd.sample.toml
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let uid = unsafe { libc::getuid() };
-    let shellex = shellexpand::tilde("~/.config/pwalarmd/pwalarmd.toml").to_string();
-    let config_path: String = if let Ok(v) = std::env::var("PWALARMD_CONFIG") {
-        v.to_string()
-    } else if Path::new(&shellex).exists() {
-        shellex
-    } else if Path::new("/etc/pwalarmd.toml").exists() {
-        "/etc/pwalarmd.toml".to_string()
-    } else {
-        beprint("could not find config file");
-        beprint("try copying /etc/xdg/pwalarmd/pwalarmd.sample.toml");
-        beprint("to ~/.config/pwalarmd/pwalarmd.toml");
-        std::process::exit(1)
-    };
-    let get_toml = || match toml::from_str(match std::fs::read_to_string(&config_path) {
-        Ok(ref s) => s,
-        Err(_) => {
-            beprint("unable to read config file, aborting");
-            std::process::exit(2)
+    // first thing, while there's only the one thread to see the
+    // environment change
+    let activated = systemd::listener().unwrap_or_else(|e| {
+        beprint(&format!("ignoring the socket passed in by systemd: {}", e));
+        None
+    });
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut replace = false;
+    match args.first().map(|a| a.as_str()) {
+        Some("--check") => {
+            let path = args.get(1).cloned().unwrap_or_else(find_config_path);
+            std::process::exit(check::check(&path));
+        }
+        Some("--replace") if args.len() == 1 => replace = true,
+        Some("--print-unit") if args.len() == 1 => {
+            print!(
+                "{}",
+                systemd::units(&std::env::current_exe()?.to_string_lossy())
+            );
+            std::process::exit(0);
+        }
+        Some(a) => {
+            beprint(&format!("unknown argument '{}'", a));
+            beprint("usage: pwalarmd [--replace | --check [CONFIG] | --print-unit]");
+            std::process::exit(1)
         }
-    }) {
+        None => {}
+    }
+    let uid = unsafe { libc::getuid() };
+    let rundir = format!("/run/user/{}/pwalarmd", uid);
+    let config_path = find_config_path();
+    let mut config: Config = match parse_config(&config_path) {
         Ok(c) => c,
-        Err(e) => {
+        Err(ConfigError::Toml(e)) => {
             beprint("invalid TOML in config file, aborting");
             beprint("TOML error shown below:");
             eprintln!("{}", e);
             std::process::exit(3)
         }
+        Err(_) => {
+            beprint("unable to read config file, aborting");
+            std::process::exit(2)
+        }
     };
-    let mut config: Config = get_toml();
-    let tmp_stderr = File::create(format!("/tmp/pwalarmd-{}.err", uid))?;
-    // TODO: kill any other pwalarmds running under the same user
-    let nd = std::env::var("PWALARMD_NODAEMON");
-    let dmzd = nd == Ok("1".to_string())
-        || (nd != Ok("0".to_string()) && config.general.daemon != Some(false));
+    let new_ids = assign_ids(&mut config, None);
+    // The ids the config file's alarms were given, in file order,
+    // so saving can tell which table belongs to which alarm
+    let mut file_ids = alarm_ids(&config);
+    std::fs::create_dir_all(&rundir)?;
+    // everything that can make startup fail is checked before the
+    // lock, so --replace never stops a working pwalarmd for nothing
+    // opened without truncating, since it may be the running pwalarmd's
+    let tmp_stderr = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(format!("/tmp/pwalarmd-{}.err", uid))?;
+    let dmzd = daemonizes(&config);
+    let cd = run_dir(&config)?;
+    let errors = validate_config(&config, &cd);
+    if !errors.is_empty() {
+        for e in errors {
+            beprint(&e);
+        }
+        beprint("invalid config, aborting");
+        std::process::exit(3)
+    }
+    let sock_path = format!("{}/pwalarmd.sock", rundir);
+    if activated.is_none() {
+        if let Err(e) = std::os::unix::net::SocketAddr::from_pathname(&sock_path) {
+            beprint(&format!("can't listen on {}: {}, aborting", sock_path, e));
+            std::process::exit(2)
+        }
+    }
+    let pid_path = PathBuf::from(format!("{}/pwalarmd.pid", rundir));
+    let mut pid_file = match pidfile::PidFile::lock(&pid_path)? {
+        Some(p) => p,
+        None if replace => replace_running(&pid_path, &sock_path)?,
+        None => {
+            beprint(&format!(
+                "pwalarmd is already running (pid {}), aborting",
+                pidfile::holder(&pid_path).map_or("unknown".to_string(), |p| p.to_string())
+            ));
+            beprint("run pwalarmd --replace to take over from it");
+            std::process::exit(6)
+        }
+    };
+    tmp_stderr.set_len(0)?;
     if dmzd {
         // TODO: more daemon settings
-        let mut cd = std::env::current_exe()?;
-        cd.pop();
         Daemonize::new()
             .stderr(tmp_stderr)
-            .working_directory(cd.clone())
+            .working_directory(cd)
             .start()?;
     }
+    pid_file.write_pid()?;
+
+    // SIGHUP reloads the config, SIGUSR1 dumps the alarms to the log,
+    // and SIGTERM/SIGINT shut down cleanly. Has to come before the
+    // output stream starts its thread, see Signals::new.
+    let signals = event::Signals::new(&[libc::SIGHUP, libc::SIGTERM, libc::SIGINT, libc::SIGUSR1])?;
 
     // TODO: better error handling
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
@@ -165,102 +334,310 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Set up initial alarm list
     let mut alarm_ring: VecDeque<LocalAlarm> = VecDeque::new();
-    let mkring =
-        |conf: &Config, ar: &mut VecDeque<LocalAlarm>| -> Result<(), Box<dyn std::error::Error>> {
-            if let Some(ref alarms) = conf.alarms {
-                for alarm in alarms {
-                    let atime = alarm.time.time.unwrap();
-                    let la = LocalAlarm {
-                        next_run_date: match determine_entry_day(atime, &alarm.repeat) {
+    let mut snoozed: Vec<SnoozedAlarm> = vec![];
+    // The alarm that rang most recently, used when snoozing without a target
+    let mut last_fired: Option<Alarm> = None;
+    let mut ringing: Vec<RingingAlarm> = vec![];
+    // Sorted by when they run out
+    let mut timers: Vec<Timer> = vec![];
+    // Alarm id -> the last occurrence skipped by SkipNext.
+    // Kept outside the ring so skips outlive config reloads.
+    let mut skipped: HashMap<String, NaiveDateTime> = HashMap::new();
+    // Alarms read from the [[Calendar]] files. They're kept out of
+    // config.alarms, so saving never copies them into the config.
+    let (mut cal_alarms, mut cal_mtimes) = load_calendars(&config);
+    let mkring = |conf: &Config,
+                  cal: &[Alarm],
+                  ar: &mut VecDeque<LocalAlarm>,
+                  skipped: &HashMap<String, NaiveDateTime>|
+     -> Result<(), Box<dyn std::error::Error>> {
+        {
+            let alarms = conf.alarms.iter().flatten().chain(cal);
+            for alarm in alarms.filter(|a| a.enabled != Some(false)) {
+                let mut nr = match determine_next_run(alarm) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                if let Some(d) = alarm.id.as_ref().and_then(|id| skipped.get(id)) {
+                    if nr <= *d {
+                        nr = match next_run(alarm, *d) {
                             Some(v) => v,
                             None => continue,
-                        },
-                        alarm: alarm.clone(),
-                    };
-                    ar.insert(ar.binary_search(&la).unwrap_or_else(|e| e), la);
+                        };
+                    }
                 }
+                let la = LocalAlarm {
+                    next_run: nr,
+                    alarm: alarm.clone(),
+                };
+                ar.insert(ar.binary_search(&la).unwrap_or_else(|e| e), la);
             }
-            Ok(())
-        };
-    mkring(&config, &mut alarm_ring)?;
-
-    let polts = |conf: &Config| {
-        if let Some(t) = conf.general.poll {
-            t
-        } else {
-            10
         }
+        Ok(())
     };
+    mkring(&config, &cal_alarms, &mut alarm_ring, &skipped)?;
+
+    // poll, tpfc and tsfc only drove the old polling loop. They're still
+    // kept and reported, so existing configs and scripts don't break.
+    let polts = |conf: &Config| conf.general.poll.unwrap_or(10);
     let mut polltime = polts(&config);
     // Set up mtime check
     let mut mtime = std::fs::metadata(&config_path)?.modified()?;
-    let tpfcs = |conf: &Config| {
-        if let Some(t) = conf.general.tpfc {
-            t
-        } else {
-            2
-        }
-    };
-    let tsfcs = |conf: &Config| {
-        if let Some(t) = conf.general.tsfc {
-            t
-        } else {
-            1
-        }
-    };
+    if new_ids {
+        save_ids(&config_path, &config, &mut file_ids, &mut mtime);
+    }
+    let tpfcs = |conf: &Config| conf.general.tpfc.unwrap_or(2);
+    let tsfcs = |conf: &Config| conf.general.tsfc.unwrap_or(1);
     let mut tpfc = tpfcs(&config);
-    let mut cpfc = tpfc;
     let mut tsfc = tsfcs(&config);
-    let mut csfc = tsfc;
-    let tgt = format!("/run/user/{}/pwalarmd/pwalarmd.sock", uid);
-    std::fs::remove_file(&tgt).unwrap_or(());
-    std::fs::create_dir_all(format!("/run/user/{}/pwalarmd", uid))?;
-    let sock = UnixListener::bind(tgt)?;
+    // A socket passed in by systemd belongs to the .socket unit, so
+    // it's left alone on exit. Otherwise, holding the pid file lock
+    // means any socket left over is stale.
+    let (sock, tgt) = match activated {
+        Some(l) => (l, None),
+        None => {
+            std::fs::remove_file(&sock_path).unwrap_or(());
+            (UnixListener::bind(&sock_path)?, Some(sock_path))
+        }
+    };
     sock.set_nonblocking(true)?;
-    let mut qbuf = Box::new([0u8; BUFFER_READ]);
+
+    // The loop sleeps until a client connects, a watched file changes,
+    // a signal arrives, or the next alarm, snooze or timer is due
+    let poller = event::Poller::new()?;
+    let wake = event::Timer::new()?;
+    let watcher = event::Watcher::new()?;
+    poller.add(sock.as_raw_fd(), TOKEN_SOCKET)?;
+    poller.add(wake.as_raw_fd(), TOKEN_TIMER)?;
+    poller.add(watcher.as_raw_fd(), TOKEN_WATCH)?;
+    poller.add(signals.as_raw_fd(), TOKEN_SIGNAL)?;
+    let mut clients: HashMap<u64, client::Client> = HashMap::new();
+    let mut next_client = TOKEN_CLIENT;
+    // set while accepting is held off after an error
+    let mut accept_at: Option<Instant> = None;
+    // for subscribed clients, sent at the end of each pass through the loop
+    let mut events: Vec<protobuf_sock::Event> = vec![];
+    // set when the config changes, since the calendars may have moved
+    let mut rewatch = true;
+    // Editors write a file in several steps (or write a new one and
+    // rename it over the old), so reloads wait for things to settle
+    let mut reload_at: Option<Instant> = None;
+    // set by SIGHUP, to reload even if nothing seems to have changed
+    let mut force_reload = false;
+    let watchdog = systemd::watchdog_interval();
+    let mut watchdog_at = watchdog.map(|w| Instant::now() + w);
+    // what systemd was last told about, to only send changes
+    let mut status_next = None;
+    systemd::notify("READY=1");
+    let mut last_reload: Option<ReloadStatus> = None;
 
     // Processing loop
     loop {
-        std::thread::sleep(Duration::from_millis(polltime));
+        if rewatch {
+            // The directories are watched rather than the files, which
+            // get replaced by atomic renames and may not exist yet.
+            // A missing directory shouldn't keep the rest from being watched.
+            for p in watched_paths(&config_path, &config) {
+                watcher.watch(parent_dir(&p)).unwrap_or(());
+            }
+            rewatch = false;
+        }
+        let next = [
+            alarm_ring.front().map(|a| a.next_run),
+            snoozed.first().map(|s| s.until),
+            timers.first().map(|t| t.until),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        wake.arm_at(next.map(local_to_system))?;
+        if status_next != Some(next) {
+            systemd::notify(&match next {
+                Some(n) => format!("STATUS=next alarm at {}", n.format("%Y-%m-%d %H:%M:%S")),
+                None => "STATUS=no alarms scheduled".to_string(),
+            });
+            status_next = Some(next);
+        }
+        // ringing alarms have to be stopped once they reach max_ring
+        let now = Instant::now();
+        let timeout = ringing
+            .iter()
+            .filter_map(|r| r.deadline)
+            .chain(reload_at)
+            .chain(accept_at)
+            .chain(watchdog_at)
+            .chain(
+                clients
+                    .values()
+                    .filter(|c| !c.subscribed)
+                    .map(|c| c.active + CLIENT_IDLE),
+            )
+            .min()
+            .map(|d| d.saturating_duration_since(now));
+        let tokens = poller.wait(timeout)?;
+        if let (Some(at), Some(w)) = (watchdog_at, watchdog) {
+            if at <= Instant::now() {
+                systemd::notify("WATCHDOG=1");
+                watchdog_at = Some(Instant::now() + w);
+            }
+        }
+        if tokens.contains(&TOKEN_TIMER) {
+            wake.drain();
+        }
+        if tokens.contains(&TOKEN_WATCH) {
+            // other files in the same directories are none of our business
+            let names = watched_paths(&config_path, &config);
+            if watcher
+                .drain()
+                .iter()
+                .any(|n| names.iter().any(|p| p.file_name() == Some(n.as_os_str())))
+            {
+                reload_at = Some(Instant::now() + RELOAD_DEBOUNCE);
+            }
+        }
+        if tokens.contains(&TOKEN_SIGNAL) {
+            for sig in signals.drain() {
+                match sig {
+                    libc::SIGHUP => {
+                        force_reload = true;
+                        reload_at = Some(Instant::now());
+                    }
+                    libc::SIGUSR1 => dump_state(&alarm_ring, &snoozed, &timers, &ringing),
+                    _ => shutdown(&mut ringing, &mut clients, tgt.as_deref()),
+                }
+            }
+        }
         // Check for config changes
-        let nmt = std::fs::metadata(&config_path)?.modified()?;
-        if cpfc == 0 {
-            if nmt > mtime {
-                mtime = nmt;
-                config = get_toml();
-                global_sound = config
-                    .general
-                    .sound
-                    .clone()
-                    .unwrap_or("assets/hyper-alarm.mp3".to_string());
+        if reload_at.is_some_and(|r| r <= Instant::now()) {
+            reload_at = None;
+            let force = std::mem::take(&mut force_reload);
+            // The file can be missing for a moment while it's replaced.
+            // Comparing for any change rather than a newer time catches
+            // files renamed into place, which can keep an older mtime.
+            let nmt = std::fs::metadata(&config_path)
+                .and_then(|m| m.modified())
+                .map_err(ConfigError::Read);
+            let mut reload = false;
+            match nmt {
+                Ok(nmt) if nmt == mtime && !force => {}
+                nmt => {
+                    let res = nmt.and_then(|nmt| {
+                        mtime = nmt;
+                        read_config(&config_path)
+                    });
+                    match res {
+                        Ok(mut nc) => {
+                            let new_ids = assign_ids(&mut nc, Some(&config));
+                            config = nc;
+                            file_ids = alarm_ids(&config);
+                            if new_ids {
+                                save_ids(&config_path, &config, &mut file_ids, &mut mtime);
+                            }
+                            global_sound = config
+                                .general
+                                .sound
+                                .clone()
+                                .unwrap_or("assets/hyper-alarm.mp3".to_string());
+                            polltime = polts(&config);
+                            tpfc = tpfcs(&config);
+                            tsfc = tsfcs(&config);
+                            rewatch = true;
+                            reload = true;
+                            last_reload = Some(ReloadStatus {
+                                time: SystemTime::now(),
+                                errors: vec![],
+                            });
+                            let mut ev = event(EventType::Reloaded, None);
+                            ev.applied = Some(true);
+                            events.push(ev);
+                        }
+                        Err(e) => {
+                            beprint("config not reloaded, keeping the current one:");
+                            for m in e.messages() {
+                                beprint(&m);
+                            }
+                            last_reload = Some(ReloadStatus {
+                                time: SystemTime::now(),
+                                errors: e.messages(),
+                            });
+                            let mut ev = event(EventType::Reloaded, None);
+                            ev.applied = Some(false);
+                            ev.errors = e.messages();
+                            events.push(ev);
+                        }
+                    }
+                }
+            }
+            // calendars are watched the same way, and reread with the config
+            if reload || force || calendar_mtimes(&config) != cal_mtimes {
+                (cal_alarms, cal_mtimes) = load_calendars(&config);
                 alarm_ring = VecDeque::new();
-                mkring(&config, &mut alarm_ring)?;
-                polltime = polts(&config);
-                tpfc = tpfcs(&config);
-                tsfc = tsfcs(&config);
+                mkring(&config, &cal_alarms, &mut alarm_ring, &skipped)?;
             }
-            cpfc = tpfc;
-        } else {
-            cpfc -= 1;
-        }
-        // Poll the socket (nonblocking)
-        // TODO: add kill command
-        if csfc == 0 {
-            match sock.accept() {
-                Ok((mut socket, _addr)) => 'L1: {
-                    socket.set_nonblocking(false)?;
-                    let rc = socket.read(&mut *qbuf)?;
-                    let res = &qbuf[..rc];
-                    let msg;
-                    match protobuf_sock::SocketRequest::parse_from_bytes(&res) {
-                        Ok(r) => msg = r,
+        }
+        if accept_at.is_some_and(|a| a <= Instant::now()) {
+            accept_at = None;
+            if let Err(e) = poller.set_writable(sock.as_raw_fd(), TOKEN_SOCKET, false) {
+                beprint(&format!("unable to accept connections again: {}", e));
+            }
+        }
+        if tokens.contains(&TOKEN_SOCKET) {
+            // every pending connection, each registered with its own token.
+            // Problems with one connection are only that connection's.
+            loop {
+                match sock.accept() {
+                    Ok(_) if clients.len() >= MAX_CLIENTS => {}
+                    Ok((stream, _addr)) => {
+                        let socket = match client::Client::new(stream) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                beprint(&format!("unable to set up a connection: {}", e));
+                                continue;
+                            }
+                        };
+                        if let Err(e) = poller.add(socket.as_raw_fd(), next_client) {
+                            beprint(&format!("unable to set up a connection: {}", e));
+                            continue;
+                        }
+                        clients.insert(next_client, socket);
+                        next_client += 1;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        beprint(&format!("unable to accept a connection: {}", e));
+                        poller.pause(sock.as_raw_fd(), TOKEN_SOCKET).unwrap_or(());
+                        accept_at = Some(Instant::now() + ACCEPT_BACKOFF);
+                        break;
+                    }
+                }
+            }
+        }
+        // Clients are served as their requests arrive and as their
+        // sockets take the responses, so none of them can hold up the rest
+        for tok in tokens.iter().filter(|t| **t >= TOKEN_CLIENT) {
+            // taken out while its requests are handled
+            let mut socket = match clients.remove(tok) {
+                Some(c) => c,
+                None => continue,
+            };
+            // there may be room for output that didn't fit before
+            socket.flush();
+            for res in socket.receive() {
+                // set by requests that change the config, for autosave
+                let mut changed = false;
+                'L1: {
+                    socket.rid = None;
+                    let msg = match protobuf_sock::SocketRequest::parse_from_bytes(&res) {
+                        Ok(r) => r,
                         Err(_) => {
-                            proto_send_error(ErrorReason::ParseFailureError, &mut socket)?;
+                            proto_send_error(ErrorReason::ParseFailureError, &mut socket);
                             break 'L1;
                         }
-                    }
+                    };
+                    socket.rid = msg.rid;
                     if msg.message.is_none() {
-                        proto_send_error(ErrorReason::MissingRequiredComponent, &mut socket)?;
+                        proto_send_error(ErrorReason::MissingRequiredComponent, &mut socket);
                         break 'L1;
                     }
                     match msg.message.unwrap() {
@@ -269,19 +646,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 proto_send_error(
                                     ErrorReason::MissingRequiredComponent,
                                     &mut socket,
-                                )?;
+                                );
                                 break 'L1;
                             }
                             let s = v.newsound.unwrap();
                             config.general.sound = Some(s.clone());
                             global_sound = s;
+                            changed = true;
                         }
                         socket_request::Message::Cpf(v) => {
                             if v.poll.is_none() && v.tpfc.is_none() && v.tsfc.is_none() {
                                 proto_send_error(
                                     ErrorReason::MissingRequiredComponent,
                                     &mut socket,
-                                )?;
+                                );
                                 break 'L1;
                             }
                             if let Some(z) = v.poll {
@@ -296,228 +674,1452 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 config.general.tsfc = Some(z as u16);
                                 tsfc = z as u16;
                             }
+                            changed = true;
                         }
                         socket_request::Message::Sn(v) => {
                             if let Some(z) = v.noti {
                                 config.general.notify = z;
+                                changed = true;
                             } else {
                                 proto_send_error(
                                     ErrorReason::MissingRequiredComponent,
                                     &mut socket,
-                                )?;
+                                );
                                 break 'L1;
                             }
                         }
                         socket_request::Message::Can(v) => {
                             config.general.custom_app_name = v.newname;
+                            changed = true;
                         }
                         socket_request::Message::Fgi(v) => {
                             if v.git.is_none() {
                                 proto_send_error(
                                     ErrorReason::MissingRequiredComponent,
                                     &mut socket,
-                                )?;
+                                );
                                 break 'L1;
                             }
                             if let Ok(e) = v.git.unwrap().enum_value() {
                                 match e {
                                     GeneralInfoType::Sound => {
-                                        proto_send_data_st(&mut socket, global_sound.clone())?
+                                        proto_send_data_st(&mut socket, global_sound.clone())
                                     }
                                     GeneralInfoType::Poll => {
-                                        proto_send_data_ui(&mut socket, polltime)?
+                                        proto_send_data_ui(&mut socket, polltime)
                                     }
                                     GeneralInfoType::Notify => {
-                                        proto_send_data_bl(&mut socket, config.general.notify)?
+                                        proto_send_data_bl(&mut socket, config.general.notify)
                                     }
                                     GeneralInfoType::AppName => proto_send_data_st(
                                         &mut socket,
                                         _get_notiname(&config).to_string(),
-                                    )?,
+                                    ),
                                     GeneralInfoType::Daemon => {
-                                        proto_send_data_bl(&mut socket, dmzd)?
+                                        proto_send_data_bl(&mut socket, dmzd)
                                     }
                                     GeneralInfoType::Tpfc => {
-                                        proto_send_data_sui(&mut socket, tpfc as u32)?
+                                        proto_send_data_sui(&mut socket, tpfc as u32)
                                     }
                                     GeneralInfoType::Tsfc => {
-                                        proto_send_data_sui(&mut socket, tsfc as u32)?
+                                        proto_send_data_sui(&mut socket, tsfc as u32)
                                     }
                                 }
                             } else {
-                                proto_send_error(ErrorReason::IllegalEnumOption, &mut socket)?;
+                                proto_send_error(ErrorReason::IllegalEnumOption, &mut socket);
                             }
                             break 'L1;
                         }
+                        socket_request::Message::Fag(_) => {
+                            let mut resp = protobuf_sock::SocketResponse::new();
+                            let mut dat = protobuf_sock::RequestSuccessWithGeneral::new();
+                            dat.sound = Some(global_sound.clone());
+                            dat.poll = Some(polltime);
+                            dat.notify = Some(config.general.notify);
+                            dat.appname = Some(_get_notiname(&config).to_string());
+                            dat.daemon = Some(dmzd);
+                            dat.tpfc = Some(tpfc as u32);
+                            dat.tsfc = Some(tsfc as u32);
+                            resp.set_swg(dat);
+                            socket.send(resp);
+                            break 'L1;
+                        }
                         socket_request::Message::Fa(_) => {
                             let mut resp = protobuf_sock::SocketResponse::new();
                             let mut dat = protobuf_sock::RequestSuccessWithAlarms::new();
                             for la in &alarm_ring {
                                 let a: Result<AlarmInfo, _> = la.alarm.clone().try_into();
                                 if a.is_err() {
-                                    proto_send_error(
-                                        ErrorReason::InternalServerError,
-                                        &mut socket,
-                                    )?;
+                                    proto_send_error(ErrorReason::InternalServerError, &mut socket);
                                     break 'L1;
                                 }
                                 dat.als.push(a.unwrap());
                             }
+                            // disabled alarms aren't in the ring, but should still be listed
+                            for c in config.alarms.iter().flatten() {
+                                if c.enabled != Some(false) {
+                                    continue;
+                                }
+                                match c.clone().try_into() {
+                                    Ok(a) => dat.als.push(a),
+                                    Err(_) => {
+                                        proto_send_error(
+                                            ErrorReason::InternalServerError,
+                                            &mut socket,
+                                        );
+                                        break 'L1;
+                                    }
+                                }
+                            }
                             resp.set_swa(dat);
-                            resp.write_to(&mut protobuf::CodedOutputStream::new(&mut socket))?;
-                            socket.flush()?;
-                            socket.set_nonblocking(true)?;
+                            socket.send(resp);
                             break 'L1;
                         }
-                        socket_request::Message::Na(v) => 'La2: {
+                        socket_request::Message::Na(v) => {
                             if let Some(a) = v.al.into_option() {
                                 let b: Result<Alarm, _> = a.try_into();
-                                if let Ok(c) = b {
-                                    let d = LocalAlarm {
-                                        // unwrap here is safe because TryFrom will always generate valid time
-                                        next_run_date: match determine_entry_day(c.time.time.unwrap(), &c.repeat) {
-                                            Some(v) => v,
-                                            // nonrepeating alarms can silent fail
-                                            None => break 'La2
-                                        },
-                                        alarm: c,
+                                // the daemon runs in its own directory, see run_dir
+                                if let Some(mut c) = b
+                                    .ok()
+                                    .filter(|c| alarm_problems(c, Path::new(".")).is_empty())
+                                {
+                                    let id = {
+                                        let used =
+                                            used_ids(&config, &alarm_ring, &snoozed, &ringing);
+                                        match c.id {
+                                            Some(ref id)
+                                                if used.contains(&id.as_str())
+                                                    || timers
+                                                        .iter()
+                                                        .any(|yz| has_id(&yz.alarm, id)) =>
+                                            {
+                                                proto_send_error(
+                                                    ErrorReason::AlreadyExists,
+                                                    &mut socket,
+                                                );
+                                                break 'L1;
+                                            }
+                                            Some(ref id) => id.clone(),
+                                            None => fresh_id(&used),
+                                        }
                                     };
-                                    alarm_ring.insert(
-                                        alarm_ring.binary_search(&d).unwrap_or_else(|e| e),
-                                        d,
-                                    );
+                                    c.id = Some(id.clone());
+                                    config.alarms.get_or_insert_with(Vec::new).push(c.clone());
+                                    changed = true;
+                                    events.push(event(EventType::Added, Some(&c)));
+                                    // nonrepeating and elapsed one-shot alarms can silent fail
+                                    // and disabled ones stay out of the ring
+                                    if let Some(nr) =
+                                        determine_next_run(&c).filter(|_| c.enabled != Some(false))
+                                    {
+                                        let d = LocalAlarm {
+                                            next_run: nr,
+                                            alarm: c,
+                                        };
+                                        alarm_ring.insert(
+                                            alarm_ring.binary_search(&d).unwrap_or_else(|e| e),
+                                            d,
+                                        );
+                                    }
+                                    proto_send_data_st(&mut socket, id);
+                                    break 'L1;
                                 } else {
                                     proto_send_error(
                                         ErrorReason::MissingRequiredComponent,
                                         &mut socket,
-                                    )?;
+                                    );
                                     break 'L1;
                                 }
                             } else {
                                 proto_send_error(
                                     ErrorReason::MissingRequiredComponent,
                                     &mut socket,
-                                )?;
+                                );
                                 break 'L1;
                             }
                         }
                         socket_request::Message::Ra(v) => {
-                            if let Some(a) = v.al.into_option() {
-                                let b: Result<Alarm, _> = a.try_into();
-                                if let Ok(c) = b {
-                                    match alarm_ring.iter().position(|yz| c == yz.alarm) {
-                                        Some(q) => alarm_ring.remove(q),
-                                        None => {
-                                            proto_send_error(ErrorReason::DoesNotExist, &mut socket)?;
-                                            break 'L1;
-                                        }
-                                    };
+                            if let Some(id) = v.id {
+                                match config
+                                    .alarms
+                                    .as_ref()
+                                    .and_then(|a| a.iter().position(|yz| has_id(yz, &id)))
+                                {
+                                    Some(q) => {
+                                        let c = config.alarms.as_mut().unwrap().remove(q);
+                                        events.push(event(EventType::Removed, Some(&c)));
+                                    }
+                                    None => {
+                                        proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                        break 'L1;
+                                    }
+                                };
+                                alarm_ring.retain(|yz| !has_id(&yz.alarm, &id));
+                                snoozed.retain(|yz| !has_id(&yz.alarm, &id));
+                                skipped.remove(&id);
+                                // so a new alarm reusing the id doesn't inherit its table
+                                for f in file_ids.iter_mut().filter(|f| f.as_ref() == Some(&id)) {
+                                    *f = None;
+                                }
+                                changed = true;
+                            } else {
+                                proto_send_error(
+                                    ErrorReason::MissingRequiredComponent,
+                                    &mut socket,
+                                );
+                                break 'L1;
+                            }
+                        }
+                        socket_request::Message::Ma(v) => {
+                            let id = match v.id {
+                                Some(ref id) => id.clone(),
+                                None => {
+                                    proto_send_error(
+                                        ErrorReason::MissingRequiredComponent,
+                                        &mut socket,
+                                    );
+                                    break 'L1;
+                                }
+                            };
+                            let time = match v.time.map(|t| wire_to_datetime(t, v.date)) {
+                                Some(Ok(t)) => Some(t),
+                                Some(Err(_)) => {
+                                    proto_send_error(
+                                        ErrorReason::MissingRequiredComponent,
+                                        &mut socket,
+                                    );
+                                    break 'L1;
+                                }
+                                None => None,
+                            };
+                            // an empty string clears the field
+                            let patch = |f: &mut Option<String>, z: Option<String>| {
+                                if let Some(z) = z {
+                                    *f = if z.is_empty() { None } else { Some(z) };
+                                }
+                            };
+                            let entry = match config
+                                .alarms
+                                .iter_mut()
+                                .flatten()
+                                .find(|yz| has_id(yz, &id))
+                            {
+                                Some(c) => c,
+                                None => {
+                                    proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                    break 'L1;
+                                }
+                            };
+                            // patched on a copy, so an invalid result leaves the alarm alone
+                            let mut c = entry.clone();
+                            patch(&mut c.title, v.title);
+                            patch(&mut c.description, v.desc);
+                            patch(&mut c.sound, v.sound);
+                            patch(&mut c.icon, v.icon);
+                            // a time and a schedule replace each other
+                            if let Some(mut t) = time {
+                                // a new time of day keeps the old date
+                                if v.date.is_none() {
+                                    t.date = c.time.and_then(|o| o.date);
+                                }
+                                c.time = Some(t);
+                                c.schedule = None;
+                            }
+                            if v.undated == Some(true) {
+                                if let Some(ref mut t) = c.time {
+                                    t.date = None;
+                                }
+                            }
+                            if v.schedule.as_ref().is_some_and(|z| !z.is_empty()) {
+                                c.time = None;
+                                c.repeat = None;
+                                c.rrule = None;
+                            }
+                            patch(&mut c.schedule, v.schedule);
+                            // and so do weekday repeats and rrules
+                            if let Some(r) = v.repeat.into_option() {
+                                c.repeat = if r.days.is_empty() {
+                                    None
+                                } else {
+                                    c.rrule = None;
+                                    Some(r.days)
+                                };
+                            }
+                            if v.rrule.as_ref().is_some_and(|z| !z.is_empty()) {
+                                c.repeat = None;
+                            }
+                            patch(&mut c.rrule, v.rrule);
+                            let problems = alarm_problems(&c, Path::new("."));
+                            if !problems.is_empty() {
+                                proto_send_error_msg(
+                                    ErrorReason::MissingRequiredComponent,
+                                    problems
+                                        .into_iter()
+                                        .map(|(_, e)| e)
+                                        .collect::<Vec<_>>()
+                                        .join("; "),
+                                    &mut socket,
+                                );
+                                break 'L1;
+                            }
+                            *entry = c.clone();
+                            changed = true;
+                            events.push(event(EventType::Modified, Some(&c)));
+                            // a new schedule starts without skips
+                            skipped.remove(&id);
+                            alarm_ring.retain(|yz| !has_id(&yz.alarm, &id));
+                            // like NewAlarm, moving a one-shot alarm into the past drops it
+                            if let Some(nr) =
+                                determine_next_run(&c).filter(|_| c.enabled != Some(false))
+                            {
+                                let d = LocalAlarm {
+                                    next_run: nr,
+                                    alarm: c,
+                                };
+                                alarm_ring
+                                    .insert(alarm_ring.binary_search(&d).unwrap_or_else(|e| e), d);
+                            }
+                        }
+                        socket_request::Message::Sz(v) => {
+                            let target = if let Some(id) = v.id {
+                                let c = last_fired
+                                    .iter()
+                                    .chain(ringing.iter().map(|yz| &yz.alarm))
+                                    .chain(alarm_ring.iter().map(|yz| &yz.alarm))
+                                    .chain(snoozed.iter().map(|yz| &yz.alarm))
+                                    .find(|yz| has_id(yz, &id))
+                                    .cloned();
+                                if let Some(c) = c {
+                                    c
                                 } else {
+                                    proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                    break 'L1;
+                                }
+                            } else if let Some(ref c) = last_fired {
+                                c.clone()
+                            } else {
+                                proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                break 'L1;
+                            };
+                            let len = v
+                                .duration
+                                .or(target.snooze)
+                                .or(config.general.snooze)
+                                .unwrap_or(DEFAULT_SNOOZE);
+                            let until = match from_now(len) {
+                                Some(u) => u,
+                                None => {
                                     proto_send_error(
                                         ErrorReason::MissingRequiredComponent,
                                         &mut socket,
-                                    )?;
+                                    );
+                                    break 'L1;
+                                }
+                            };
+                            if last_fired.as_ref().is_some_and(|yz| yz.id == target.id) {
+                                last_fired = None;
+                            }
+                            // re-snoozing replaces the pending snooze
+                            snoozed.retain(|yz| yz.alarm.id != target.id);
+                            ringing.retain(|yz| yz.alarm.id != target.id);
+                            let sa = SnoozedAlarm {
+                                until,
+                                alarm: target,
+                            };
+                            let mut ev = event(EventType::Snoozed, Some(&sa.alarm));
+                            ev.until = epoch_secs(local_to_system(sa.until));
+                            events.push(ev);
+                            snoozed.insert(snoozed.partition_point(|yz| yz.until <= sa.until), sa);
+                        }
+                        socket_request::Message::Dm(v) => {
+                            if let Some(id) = v.id {
+                                let rc = ringing.len();
+                                for r in ringing.iter().filter(|yz| has_id(&yz.alarm, &id)) {
+                                    events.push(event(EventType::Dismissed, Some(&r.alarm)));
+                                }
+                                ringing.retain(|yz| !has_id(&yz.alarm, &id));
+                                if rc == ringing.len() {
+                                    proto_send_error(ErrorReason::DoesNotExist, &mut socket);
                                     break 'L1;
                                 }
+                                if last_fired.as_ref().is_some_and(|yz| has_id(yz, &id)) {
+                                    last_fired = None;
+                                }
                             } else {
+                                if ringing.is_empty() {
+                                    proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                    break 'L1;
+                                }
+                                for r in &ringing {
+                                    events.push(event(EventType::Dismissed, Some(&r.alarm)));
+                                }
+                                ringing.clear();
+                                last_fired = None;
+                            }
+                        }
+                        socket_request::Message::Ea(v) => {
+                            if v.id.is_none() {
                                 proto_send_error(
                                     ErrorReason::MissingRequiredComponent,
                                     &mut socket,
-                                )?;
+                                );
+                                break 'L1;
+                            }
+                            let id = v.id.unwrap();
+                            if !set_enabled(&mut config, &mut alarm_ring, &mut snoozed, &id, true) {
+                                proto_send_error(ErrorReason::DoesNotExist, &mut socket);
                                 break 'L1;
                             }
+                            changed = true;
+                            let c = config.alarms.iter().flatten().find(|yz| has_id(yz, &id));
+                            events.push(event(EventType::Modified, c));
                         }
-                        socket_request::Message::Ks(_) => {
-                            std::process::exit(0);
+                        socket_request::Message::Da(v) => {
+                            if v.id.is_none() {
+                                proto_send_error(
+                                    ErrorReason::MissingRequiredComponent,
+                                    &mut socket,
+                                );
+                                break 'L1;
+                            }
+                            let id = v.id.unwrap();
+                            if !set_enabled(&mut config, &mut alarm_ring, &mut snoozed, &id, false)
+                            {
+                                proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                break 'L1;
+                            }
+                            changed = true;
+                            let c = config.alarms.iter().flatten().find(|yz| has_id(yz, &id));
+                            events.push(event(EventType::Modified, c));
                         }
-                    }
-                    proto_send_success(&mut socket)?;
-                }
-                Err(e) => {
-                    if e.kind() != std::io::ErrorKind::WouldBlock {
-                        beprint("unknown socket pickup error");
-                        std::process::exit(5);
-                    }
-                }
-            }
-            csfc = tsfc;
-        } else {
-            csfc -= 1;
-        }
-        // Examine alarm
-        if alarm_ring.len() == 0 {
-            continue;
-        }
-        let cdt = Local::now();
-        let cat = alarm_ring[0].alarm.time.time.unwrap();
-        // TODO: set a maximum delta under which alarms can run (10 mins?)
-        if alarm_ring[0].next_run_date <= cdt.date_naive()
-            && NaiveTime::from_hms_opt(cat.hour.into(), cat.minute.into(), cat.second.into())
-                .unwrap()
-                <= cdt.time()
-        {
-            let mut a = alarm_ring.pop_front().unwrap();
-            // An expensive operation yes, but it's only run essentially once per 24 hours max
-            // TODO: better caching of loaded sounds
-            stream_handle.play_raw(if let Some(ref p) = a.alarm.sound {
-                loadsnd(p.clone())?
-            } else {
-                loadsnd(global_sound.clone())?
-            })?;
-            // TODO: add another condition once icons are added
-            if config.general.notify && (a.alarm.title.is_some() || a.alarm.description.is_some()) {
-                let mut noti = Notification::new();
-                if let Some(ref t) = a.alarm.title {
-                    noti.summary(t);
-                }
-                if let Some(ref t) = a.alarm.description {
-                    noti.body(t);
-                }
-                if let Some(ref t) = a.alarm.icon {
-                    noti.icon(t);
-                }
-                noti.appname(_get_notiname(&config));
-                noti.show()?;
-            }
-            a.next_run_date = a.next_run_date.succ_opt().ok_or("out of dates")?;
-            // this *can* be expensive, but unless the user has tons of
-            // weird repeat schedules, it should be cheap
-            // best-case O(log n), worst case O(n)
-            alarm_ring.insert(alarm_ring.binary_search(&a).unwrap_or_else(|e| e), a);
-        }
-    }
-
-    #[allow(unreachable_code)]
-    Ok(())
-}
-
-fn _get_notiname<'a>(c: &'a Config) -> &'a str {
-    if let Some(ref s) = c.general.custom_app_name {
-        s
-    } else {
-        "pwalarmd"
-    }
-}
-
-fn find_next_rep(mut base: NaiveDate, rep: &Option<Vec<String>>) -> Option<NaiveDate> {
-    if let Some(r) = rep {
-        for _ in 0..r.len() {
-            if r.contains(&weekday_to_str(base.weekday())) {
-                return Some(base);
-            }
-            // This only fails if we run out of representable dates,
-            // at which point we have much bigger problems
-            base = base.succ_opt().unwrap();
-        }
-        return None;
-    } else {
-        return Some(base);
-    }
-}
-
-fn beprint(msg: &str) {
-    eprint!("{}", "pwalarmd".yellow().bold());
-    eprintln!(": {}", msg.bright_red());
+                        socket_request::Message::Sk(v) => {
+                            let id = match v.id {
+                                Some(ref id) => id.clone(),
+                                None => {
+                                    proto_send_error(
+                                        ErrorReason::MissingRequiredComponent,
+                                        &mut socket,
+                                    );
+                                    break 'L1;
+                                }
+                            };
+                            let q = match alarm_ring.iter().position(|yz| has_id(&yz.alarm, &id)) {
+                                Some(q) => q,
+                                None => {
+                                    proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                    break 'L1;
+                                }
+                            };
+                            let count = v.count.unwrap_or(1);
+                            if !(1..=MAX_SKIP).contains(&count) {
+                                proto_send_error_msg(
+                                    ErrorReason::MissingRequiredComponent,
+                                    format!("can only skip 1 to {} occurrences", MAX_SKIP),
+                                    &mut socket,
+                                );
+                                break 'L1;
+                            }
+                            let (last, nr) = match skip_runs(
+                                &alarm_ring[q].alarm,
+                                alarm_ring[q].next_run,
+                                count,
+                            ) {
+                                Some(z) => z,
+                                // one-shot alarms have no next occurrence to skip to
+                                None => {
+                                    proto_send_error(ErrorReason::NotRepeating, &mut socket);
+                                    break 'L1;
+                                }
+                            };
+                            let mut a = alarm_ring.remove(q).unwrap();
+                            a.next_run = nr;
+                            skipped.insert(id, last);
+                            events.push(event(EventType::Modified, Some(&a.alarm)));
+                            alarm_ring
+                                .insert(alarm_ring.binary_search(&a).unwrap_or_else(|e| e), a);
+                            proto_send_data_st(
+                                &mut socket,
+                                nr.format("%Y-%m-%d %H:%M").to_string(),
+                            );
+                            break 'L1;
+                        }
+                        socket_request::Message::Nt(v) => {
+                            let dur = match v.duration {
+                                Some(d) => d,
+                                None => {
+                                    proto_send_error(
+                                        ErrorReason::MissingRequiredComponent,
+                                        &mut socket,
+                                    );
+                                    break 'L1;
+                                }
+                            };
+                            let id = {
+                                let used = used_ids(&config, &alarm_ring, &snoozed, &ringing);
+                                (1u32..)
+                                    .map(|n| format!("t{}", n))
+                                    .find(|n| {
+                                        !used.contains(&n.as_str())
+                                            && !timers.iter().any(|yz| has_id(&yz.alarm, n))
+                                    })
+                                    .unwrap()
+                            };
+                            // the date has to fit in a config, like any alarm's
+                            let (until, time) = match from_now(dur).and_then(|u| {
+                                wire_to_datetime(
+                                    u.num_seconds_from_midnight(),
+                                    Some(date_to_days(u.date())),
+                                )
+                                .ok()
+                                .map(|t| (u, t))
+                            }) {
+                                Some(ut) => ut,
+                                None => {
+                                    proto_send_error(
+                                        ErrorReason::MissingRequiredComponent,
+                                        &mut socket,
+                                    );
+                                    break 'L1;
+                                }
+                            };
+                            let tm = Timer {
+                                until,
+                                duration: dur,
+                                alarm: Alarm {
+                                    id: Some(id.clone()),
+                                    title: v.title,
+                                    description: v.desc,
+                                    time: Some(time),
+                                    repeat: None,
+                                    schedule: None,
+                                    rrule: None,
+                                    sound: v.sound,
+                                    icon: v.icon,
+                                    snooze: None,
+                                    max_ring: None,
+                                    volume: None,
+                                    ramp_seconds: None,
+                                    enabled: None,
+                                },
+                            };
+                            if !alarm_problems(&tm.alarm, Path::new(".")).is_empty() {
+                                proto_send_error(
+                                    ErrorReason::MissingRequiredComponent,
+                                    &mut socket,
+                                );
+                                break 'L1;
+                            }
+                            events.push(event(EventType::Added, Some(&tm.alarm)));
+                            timers.insert(timers.partition_point(|yz| yz.until <= tm.until), tm);
+                            proto_send_data_st(&mut socket, id);
+                            break 'L1;
+                        }
+                        socket_request::Message::Ft(_) => {
+                            let now = Local::now().naive_local();
+                            let mut resp = protobuf_sock::SocketResponse::new();
+                            let mut dat = protobuf_sock::RequestSuccessWithTimers::new();
+                            for tm in &timers {
+                                let mut ti = TimerInfo::new();
+                                ti.id = tm.alarm.id.clone();
+                                ti.title = tm.alarm.title.clone();
+                                ti.duration = Some(tm.duration);
+                                ti.remaining = Some((tm.until - now).num_seconds().max(0) as u64);
+                                dat.tms.push(ti);
+                            }
+                            resp.set_swt(dat);
+                            socket.send(resp);
+                            break 'L1;
+                        }
+                        socket_request::Message::Frs(_) => {
+                            let mut resp = protobuf_sock::SocketResponse::new();
+                            let mut dat = protobuf_sock::RequestSuccessWithReloadStatus::new();
+                            if let Some(ref r) = last_reload {
+                                dat.time = epoch_secs(r.time);
+                                dat.applied = Some(r.errors.is_empty());
+                                dat.errors = r.errors.clone();
+                            }
+                            resp.set_swr(dat);
+                            socket.send(resp);
+                            break 'L1;
+                        }
+                        socket_request::Message::Ct(v) => {
+                            if let Some(id) = v.id {
+                                match timers.iter().position(|yz| has_id(&yz.alarm, &id)) {
+                                    Some(q) => {
+                                        let tm = timers.remove(q);
+                                        events.push(event(EventType::Removed, Some(&tm.alarm)));
+                                    }
+                                    None => {
+                                        proto_send_error(ErrorReason::DoesNotExist, &mut socket);
+                                        break 'L1;
+                                    }
+                                }
+                            } else {
+                                proto_send_error(
+                                    ErrorReason::MissingRequiredComponent,
+                                    &mut socket,
+                                );
+                                break 'L1;
+                            }
+                        }
+                        socket_request::Message::Sub(_) => {
+                            socket.subscribed = true;
+                        }
+                        socket_request::Message::Sv(_) => {
+                            if let Err(e) = save_config(&config_path, &config, &file_ids) {
+                                beprint(&format!("unable to save config: {}", e));
+                                proto_send_error(ErrorReason::WriteFailure, &mut socket);
+                                break 'L1;
+                            }
+                            // don't reload what we just wrote
+                            note_saved(&config_path, &mut mtime);
+                            file_ids = alarm_ids(&config);
+                        }
+                        socket_request::Message::Ks(_) => {
+                            shutdown(&mut ringing, &mut clients, tgt.as_deref());
+                        }
+                    }
+                    proto_send_success(&mut socket);
+                }
+                if changed && config.general.autosave == Some(true) {
+                    match save_config(&config_path, &config, &file_ids) {
+                        Ok(()) => {
+                            note_saved(&config_path, &mut mtime);
+                            file_ids = alarm_ids(&config);
+                        }
+                        Err(e) => beprint(&format!("unable to autosave config: {}", e)),
+                    }
+                }
+            }
+            if socket.garbled() {
+                socket.rid = None;
+                proto_send_error(ErrorReason::ParseFailureError, &mut socket);
+                socket.close();
+            }
+            if socket.is_open() {
+                match poller.set_writable(socket.as_raw_fd(), *tok, socket.wants_write()) {
+                    Ok(()) => {
+                        clients.insert(*tok, socket);
+                    }
+                    Err(e) => beprint(&format!("dropping a connection: {}", e)),
+                }
+            }
+        }
+        let now = Instant::now();
+        // a client that's gone quiet may be stuck; it can always reconnect.
+        // Subscribers are expected to be quiet.
+        clients.retain(|_, c| c.subscribed || now < c.active + CLIENT_IDLE);
+        // Stop alarms that have been ringing for too long
+        ringing.retain(|r| r.deadline.map_or(true, |d| d > now));
+        let cdt = Local::now();
+        // Examine snoozed alarms
+        while !snoozed.is_empty() && snoozed[0].until <= cdt.naive_local() {
+            let sa = snoozed.remove(0);
+            last_fired = Some(sa.alarm.clone());
+            events.push(event(EventType::Fired, Some(&sa.alarm)));
+            start_ringing(
+                &mut ringing,
+                sa.alarm,
+                &config,
+                &stream_handle,
+                &global_sound,
+            );
+        }
+        // Examine timers
+        while !timers.is_empty() && timers[0].until <= cdt.naive_local() {
+            let tm = timers.remove(0);
+            last_fired = Some(tm.alarm.clone());
+            events.push(event(EventType::Fired, Some(&tm.alarm)));
+            start_ringing(
+                &mut ringing,
+                tm.alarm,
+                &config,
+                &stream_handle,
+                &global_sound,
+            );
+        }
+        // Examine alarms
+        // TODO: set a maximum delta under which alarms can run (10 mins?)
+        while alarm_ring
+            .front()
+            .is_some_and(|a| a.next_run <= cdt.naive_local())
+        {
+            let mut a = alarm_ring.pop_front().unwrap();
+            if let Some(ref id) = a.alarm.id {
+                skipped.remove(id);
+            }
+            last_fired = Some(a.alarm.clone());
+            events.push(event(EventType::Fired, Some(&a.alarm)));
+            start_ringing(
+                &mut ringing,
+                a.alarm.clone(),
+                &config,
+                &stream_handle,
+                &global_sound,
+            );
+            // one-shot alarms retire once they have rung. Counting from now
+            // rather than the missed time keeps frequent schedules from
+            // ringing over and over to catch up after the system sleeps.
+            a.next_run = match next_run(&a.alarm, max(a.next_run, cdt.naive_local())) {
+                Some(v) => v,
+                None => continue,
+            };
+            // this *can* be expensive, but unless the user has tons of
+            // weird repeat schedules, it should be cheap
+            // best-case O(log n), worst case O(n)
+            alarm_ring.insert(alarm_ring.binary_search(&a).unwrap_or_else(|e| e), a);
+        }
+        if !events.is_empty() {
+            publish(&mut clients, &mut events);
+            clients.retain(|tok, c| {
+                !c.subscribed
+                    || poller
+                        .set_writable(c.as_raw_fd(), *tok, c.wants_write())
+                        .map_err(|e| beprint(&format!("dropping a subscriber: {}", e)))
+                        .is_ok()
+            });
+        }
+    }
+
+    #[allow(unreachable_code)]
+    Ok(())
+}
+
+// A sound that can't be played is logged rather than returned, so one
+// broken alarm can't stop the daemon and every other alarm with it
+fn start_ringing(
+    ringing: &mut Vec<RingingAlarm>,
+    alarm: Alarm,
+    config: &Config,
+    stream_handle: &OutputStreamHandle,
+    global_sound: &str,
+) {
+    let name = alarm_name(&alarm).to_string();
+    match ring(alarm, config, stream_handle, global_sound) {
+        Ok(r) => add_ringing(ringing, r),
+        Err(e) => beprint(&format!("unable to ring alarm \"{}\": {}", name, e)),
+    }
+}
+
+// An alarm that rings again while it's still ringing (say, a snooze that
+// ran out) starts over instead of playing twice.
+fn add_ringing(ringing: &mut Vec<RingingAlarm>, r: RingingAlarm) {
+    if let Some(ref id) = r.alarm.id {
+        ringing.retain(|o| !has_id(&o.alarm, id));
+    }
+    ringing.push(r);
+}
+
+fn ring(
+    alarm: Alarm,
+    config: &Config,
+    stream_handle: &OutputStreamHandle,
+    global_sound: &str,
+) -> Result<RingingAlarm, Box<dyn std::error::Error>> {
+    // An expensive operation yes, but it's only run essentially once per 24 hours max
+    // TODO: better caching of loaded sounds
+    let sink = Sink::try_new(stream_handle)?;
+    let src = if let Some(ref p) = alarm.sound {
+        loadsnd(p.clone())?
+    } else {
+        loadsnd(global_sound.to_string())?
+    }
+    .repeat_infinite();
+    let vol = alarm.volume.map_or(1.0, |v| min(v, 100) as f32 / 100.0);
+    match alarm.ramp_seconds {
+        Some(r) if r > 0 => sink.append(src.fade_in(Duration::from_secs(r)).amplify(vol)),
+        _ => sink.append(src.amplify(vol)),
+    }
+    // TODO: add another condition once icons are added
+    if config.general.notify && (alarm.title.is_some() || alarm.description.is_some()) {
+        let mut noti = Notification::new();
+        if let Some(ref t) = alarm.title {
+            noti.summary(t);
+        }
+        if let Some(ref t) = alarm.description {
+            noti.body(t);
+        }
+        if let Some(ref t) = alarm.icon {
+            noti.icon(t);
+        }
+        noti.appname(_get_notiname(config));
+        // the alarm still rings without a notification daemon
+        if let Err(e) = noti.show() {
+            beprint(&format!("unable to show notification: {}", e));
+        }
+    }
+    let mr = alarm
+        .max_ring
+        .or(config.general.max_ring)
+        .unwrap_or(DEFAULT_MAX_RING);
+    Ok(RingingAlarm {
+        alarm,
+        sink,
+        // too far off to represent is as good as never
+        deadline: if mr == 0 {
+            None
+        } else {
+            Instant::now().checked_add(Duration::from_secs(mr))
+        },
+    })
+}
+
+fn _get_notiname(c: &Config) -> &str {
+    if let Some(ref s) = c.general.custom_app_name {
+        s
+    } else {
+        "pwalarmd"
+    }
+}
+
+// Takes in the mtime of the config pwalarmd just saved, so the watcher
+// doesn't reload it. If that can't be read, the old mtime stays and
+// the file is only reread, which does no harm.
+fn note_saved(path: &str, mtime: &mut SystemTime) {
+    match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(m) => *mtime = m,
+        Err(e) => beprint(&format!("unable to check the saved config: {}", e)),
+    }
+}
+
+// Writes the ids generated for alarms that had none into the config
+// file, so the next start hands out the same ones instead of numbering
+// the alarms again in file order. The rest of the file is as it was read.
+fn save_ids(path: &str, conf: &Config, file_ids: &mut Vec<Option<String>>, mtime: &mut SystemTime) {
+    match save_config(path, conf, file_ids) {
+        Ok(()) => {
+            note_saved(path, mtime);
+            *file_ids = alarm_ids(conf);
+        }
+        Err(e) => beprint(&format!("unable to save the generated alarm ids: {}", e)),
+    }
+}
+
+// Writes conf back to the config file at path. The existing document is
+// edited rather than regenerated, so comments and formatting around
+// unchanged values survive. The result is written to a temporary file
+// and renamed over the original.
+fn save_config(
+    path: &str,
+    conf: &Config,
+    file_ids: &[Option<String>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // write through symlinks (e.g. managed dotfiles) instead of replacing them
+    let path = std::fs::canonicalize(path)?;
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::access(cpath.as_ptr(), libc::W_OK) } != 0 {
+        return Err("no write access to config file".into());
+    }
+    let mut doc: DocumentMut = std::fs::read_to_string(&path)?.parse()?;
+    update_config(&mut doc, conf, file_ids)?;
+    let tmp = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let res = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut f = File::create(&tmp)?;
+        f.write_all(doc.to_string().as_bytes())?;
+        f.sync_all()?;
+        std::fs::set_permissions(&tmp, std::fs::metadata(&path)?.permissions())?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        std::fs::remove_file(&tmp).unwrap_or(());
+    }
+    res
+}
+
+// Writes conf into the parsed config file, keeping the comments, key order
+// and table layout of whatever is already there.
+fn update_config(
+    doc: &mut DocumentMut,
+    conf: &Config,
+    file_ids: &[Option<String>],
+) -> Result<(), toml_edit::ser::Error> {
+    let general = toml_edit::ser::to_document(&conf.general)?;
+    match doc.get_mut("General").and_then(|i| i.as_table_mut()) {
+        Some(t) => merge_table(t, general.as_table()),
+        None => {
+            doc.insert("General", Item::Table(general.as_table().clone()));
+        }
+    }
+    // Tables are matched up with alarms by id; tables without one
+    // use the id that was generated for them when the file was loaded
+    let mut old: Vec<(Option<String>, Table)> = match doc.get_mut("Alarm") {
+        Some(Item::ArrayOfTables(a)) => std::mem::take(a)
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let id = match t.get("id").and_then(|v| v.as_str()) {
+                    Some(id) => Some(id.to_string()),
+                    None => file_ids.get(i).cloned().flatten(),
+                };
+                (id, t)
+            })
+            .collect(),
+        _ => vec![],
+    };
+    // new tables go right after the alarm before them, or where the
+    // first alarm used to be
+    let mut pos = old.first().and_then(|(_, t)| t.position());
+    let mut aot = ArrayOfTables::new();
+    for a in conf.alarms.iter().flatten() {
+        let mut t = match old.iter().position(|(id, _)| a.id.is_some() && *id == a.id) {
+            Some(i) => old.remove(i).1,
+            None => Table::new(),
+        };
+        merge_table(&mut t, toml_edit::ser::to_document(a)?.as_table());
+        match (t.position(), pos) {
+            (Some(p), _) => pos = Some(p),
+            (None, Some(p)) => t.set_position(p),
+            (None, None) => {}
+        }
+        aot.push(t);
+    }
+    // inserting over the old array keeps its place among the other tables
+    if aot.is_empty() {
+        doc.remove("Alarm");
+    } else {
+        doc.insert("Alarm", Item::ArrayOfTables(aot));
+    }
+    Ok(())
+}
+
+// Copies the values of src into dst and drops keys src doesn't have.
+// Values that haven't changed are left alone, keeping their comments.
+fn merge_table(dst: &mut Table, src: &Table) {
+    let stale: Vec<String> = dst
+        .iter()
+        .map(|(k, _)| k.to_string())
+        .filter(|k| !src.contains_key(k))
+        .collect();
+    for k in stale {
+        dst.remove(&k);
+    }
+    for (k, v) in src.iter() {
+        let v = match v.as_value() {
+            Some(v) => v,
+            None => continue,
+        };
+        match dst.get_mut(k).and_then(|i| i.as_value_mut()) {
+            Some(old) if same_value(old, v) => {}
+            Some(old) => {
+                let decor = old.decor().clone();
+                *old = v.clone();
+                *old.decor_mut() = decor;
+            }
+            None => {
+                dst.insert(k, Item::Value(v.clone()));
+            }
+        }
+    }
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(x), Value::String(y)) => x.value() == y.value(),
+        (Value::Integer(x), Value::Integer(y)) => x.value() == y.value(),
+        (Value::Boolean(x), Value::Boolean(y)) => x.value() == y.value(),
+        (Value::Datetime(x), Value::Datetime(y)) => x.value() == y.value(),
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(p, q)| same_value(p, q))
+        }
+        _ => false,
+    }
+}
+
+// Enabling an alarm puts it back in the ring; disabling takes it out,
+// along with any pending snooze. Returns false if there is no such alarm.
+fn set_enabled(
+    conf: &mut Config,
+    ar: &mut VecDeque<LocalAlarm>,
+    sn: &mut Vec<SnoozedAlarm>,
+    id: &str,
+    on: bool,
+) -> bool {
+    let c = match conf.alarms.iter_mut().flatten().find(|a| has_id(a, id)) {
+        Some(c) => c,
+        None => return false,
+    };
+    // leave the key out of the config unless the alarm is disabled
+    c.enabled = if on { None } else { Some(false) };
+    let c = c.clone();
+    ar.retain(|la| !has_id(&la.alarm, id));
+    if !on {
+        sn.retain(|sa| !has_id(&sa.alarm, id));
+    } else if let Some(nr) = determine_next_run(&c) {
+        let la = LocalAlarm {
+            next_run: nr,
+            alarm: c,
+        };
+        ar.insert(ar.binary_search(&la).unwrap_or_else(|e| e), la);
+    }
+    true
+}
+
+// Asks the running pwalarmd to exit, the same way pwalarmctl kill
+// does, and waits for its pid file lock
+fn replace_running(
+    pid_path: &Path,
+    sock_path: &str,
+) -> Result<pidfile::PidFile, Box<dyn std::error::Error>> {
+    let pid = pidfile::holder(pid_path).map_or("unknown".to_string(), |p| p.to_string());
+    match UnixStream::connect(sock_path) {
+        Ok(mut socket) => {
+            let mut sr = protobuf_sock::SocketRequest::new();
+            sr.set_ks(protobuf_sock::KillSwitch::new());
+            frame::write_frame(&mut socket, &sr)?;
+        }
+        Err(e) => {
+            beprint(&format!(
+                "unable to reach the running pwalarmd (pid {}): {}, aborting",
+                pid, e
+            ));
+            std::process::exit(6)
+        }
+    }
+    let until = Instant::now() + REPLACE_TIMEOUT;
+    while Instant::now() < until {
+        if let Some(p) = pidfile::PidFile::lock(pid_path)? {
+            return Ok(p);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    beprint(&format!(
+        "the running pwalarmd (pid {}) didn't exit, aborting",
+        pid
+    ));
+    std::process::exit(6)
+}
+
+fn find_config_path() -> String {
+    let shellex = shellexpand::tilde("~/.config/pwalarmd/pwalarmd.toml").to_string();
+    if let Ok(v) = std::env::var("PWALARMD_CONFIG") {
+        v.to_string()
+    } else if Path::new(&shellex).exists() {
+        shellex
+    } else if Path::new("/etc/pwalarmd.toml").exists() {
+        "/etc/pwalarmd.toml".to_string()
+    } else {
+        beprint("could not find config file");
+        beprint("try copying /etc/xdg/pwalarmd/pwalarmd.sample.toml");
+        beprint("to ~/.config/pwalarmd/pwalarmd.toml");
+        std::process::exit(1)
+    }
+}
+
+fn daemonizes(conf: &Config) -> bool {
+    // systemd already runs us in the background
+    if systemd::managed() {
+        return false;
+    }
+    let nd = std::env::var("PWALARMD_NODAEMON");
+    nd == Ok("1".to_string()) || (nd != Ok("0".to_string()) && conf.general.daemon != Some(false))
+}
+
+// Where relative sound and icon paths are taken from: daemonizing
+// changes into the binary's directory
+fn run_dir(conf: &Config) -> std::io::Result<PathBuf> {
+    if daemonizes(conf) {
+        let mut cd = std::env::current_exe()?;
+        cd.pop();
+        Ok(cd)
+    } else {
+        Ok(PathBuf::from("."))
+    }
+}
+
+fn parse_config(path: &str) -> Result<Config, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(ConfigError::Read)?;
+    toml::from_str(&text).map_err(ConfigError::Toml)
+}
+
+// Reads and checks the config, without touching the running one, so a
+// bad edit on reload can be reported instead of taking the daemon down
+fn read_config(path: &str) -> Result<Config, ConfigError> {
+    let c = parse_config(path)?;
+    let errors = validate_config(&c, Path::new("."));
+    if errors.is_empty() {
+        Ok(c)
+    } else {
+        Err(ConfigError::Invalid(errors))
+    }
+}
+
+// Everything wrong with the config that serde can't catch, with
+// relative paths taken from base
+fn validate_config(conf: &Config, base: &Path) -> Vec<String> {
+    let mut errors = vec![];
+    if let Some(ref s) = conf.general.sound {
+        if !base.join(s).is_file() {
+            errors.push(format!("sound file \"{}\" doesn't exist", s));
+        }
+    }
+    for a in conf.alarms.iter().flatten() {
+        for (_, e) in alarm_problems(a, base) {
+            errors.push(format!("alarm \"{}\" {}", alarm_name(a), e));
+        }
+    }
+    errors
+}
+
+// What's wrong with one alarm, each with the key it's about ("" for the
+// alarm as a whole), so --check can point at the right line
+fn alarm_problems(a: &Alarm, base: &Path) -> Vec<(&'static str, String)> {
+    let mut problems = vec![];
+    if let Err(e) = check_alarm(a) {
+        problems.push((
+            if check_repeat(a).is_err() {
+                "repeat"
+            } else {
+                ""
+            },
+            e,
+        ));
+    }
+    if let Some(ref s) = a.sound {
+        if !base.join(s).is_file() {
+            problems.push((
+                "sound",
+                format!("has a sound file that doesn't exist: \"{}\"", s),
+            ));
+        }
+    }
+    if let Some(ref i) = a.icon {
+        if !valid_icon(i, base) {
+            problems.push(("icon", format!("has an invalid icon \"{}\"", i)));
+        }
+    }
+    problems
+}
+
+fn alarm_name(a: &Alarm) -> &str {
+    a.title
+        .as_deref()
+        .or(a.id.as_deref())
+        .unwrap_or("(untitled)")
+}
+
+// Icons are either a path to an image or the name of one from the
+// icon theme, like "alarm-symbolic"
+fn valid_icon(icon: &str, base: &Path) -> bool {
+    if icon.contains('/') {
+        base.join(icon).is_file()
+    } else {
+        !icon.is_empty()
+            && icon
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    }
+}
+
+// The config and calendar files, along with what they link to: an
+// edit through a symlink changes the target, in the target's directory
+fn watched_paths(config_path: &str, conf: &Config) -> Vec<PathBuf> {
+    std::iter::once(config_path.to_string())
+        .chain(calendar_paths(conf))
+        .flat_map(|p| {
+            let p = PathBuf::from(p);
+            let target = std::fs::canonicalize(&p).ok().filter(|t| *t != p);
+            std::iter::once(p).chain(target)
+        })
+        .collect()
+}
+
+// The directory a config or calendar file is in, for watching
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+fn calendar_paths(conf: &Config) -> Vec<String> {
+    conf.calendars
+        .iter()
+        .flatten()
+        .map(|c| shellexpand::tilde(&c.path).to_string())
+        .collect()
+}
+
+fn calendar_mtimes(conf: &Config) -> Vec<Option<SystemTime>> {
+    calendar_paths(conf)
+        .iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// Calendars are outside files, so a broken one is logged and
+// skipped rather than taking the daemon down
+fn load_calendars(conf: &Config) -> (Vec<Alarm>, Vec<Option<SystemTime>>) {
+    let mtimes = calendar_mtimes(conf);
+    let mut used: Vec<String> = conf
+        .alarms
+        .iter()
+        .flatten()
+        .filter_map(|a| a.id.clone())
+        .collect();
+    let mut alarms = vec![];
+    for p in calendar_paths(conf) {
+        let ics = match std::fs::read_to_string(&p)
+            .map_err(|e| e.to_string())
+            .and_then(|t| ical::parse_ics(&t))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                beprint(&format!("unable to read calendar {}: {}", p, e));
+                continue;
+            }
+        };
+        for ia in ics {
+            let mut a = match ics_to_alarm(ia) {
+                Ok(a) => a,
+                Err(e) => {
+                    beprint(&format!("skipping alarm in calendar {}: {}", p, e));
+                    continue;
+                }
+            };
+            if a.id.as_ref().map_or(true, |id| used.contains(id)) {
+                a.id = Some(fresh_id(
+                    &used.iter().map(|u| u.as_str()).collect::<Vec<_>>(),
+                ));
+            }
+            used.push(a.id.clone().unwrap());
+            alarms.push(a);
+        }
+    }
+    (alarms, mtimes)
+}
+
+fn ics_to_alarm(ia: ical::IcsAlarm) -> Result<Alarm, Box<dyn std::error::Error>> {
+    let a = Alarm {
+        id: ia.id,
+        title: ia.title,
+        description: ia.description,
+        time: Some(wire_to_datetime(
+            ia.at.num_seconds_from_midnight(),
+            Some(date_to_days(ia.at.date())),
+        )?),
+        repeat: None,
+        schedule: None,
+        rrule: ia.rrule,
+        sound: None,
+        icon: None,
+        snooze: None,
+        max_ring: None,
+        volume: None,
+        ramp_seconds: None,
+        enabled: None,
+    };
+    check_alarm(&a)?;
+    Ok(a)
+}
+
+fn alarm_ids(conf: &Config) -> Vec<Option<String>> {
+    conf.alarms.iter().flatten().map(|a| a.id.clone()).collect()
+}
+
+fn has_id(alarm: &Alarm, id: &str) -> bool {
+    alarm.id.as_deref() == Some(id)
+}
+
+// Alarms compared without their ids
+fn same_alarm(a: &Alarm, b: &Alarm) -> bool {
+    Alarm {
+        id: None,
+        ..a.clone()
+    } == Alarm {
+        id: None,
+        ..b.clone()
+    }
+}
+
+fn fresh_id(used: &[&str]) -> String {
+    (1u32..)
+        .map(|n| n.to_string())
+        .find(|n| !used.contains(&n.as_str()))
+        .unwrap()
+}
+
+fn used_ids<'a>(
+    conf: &'a Config,
+    ar: &'a VecDeque<LocalAlarm>,
+    sn: &'a [SnoozedAlarm],
+    rn: &'a [RingingAlarm],
+) -> Vec<&'a str> {
+    conf.alarms
+        .iter()
+        .flatten()
+        .chain(ar.iter().map(|la| &la.alarm))
+        .chain(sn.iter().map(|sa| &sa.alarm))
+        .chain(rn.iter().map(|ra| &ra.alarm))
+        .filter_map(|a| a.id.as_deref())
+        .collect()
+}
+
+// Alarms without an id in the config get one generated. Unchanged alarms
+// keep the id they had in the previous config, so ids stay stable across reloads.
+// Returns whether any alarm was given an id, which then wants saving.
+fn assign_ids(conf: &mut Config, old: Option<&Config>) -> bool {
+    let alarms = match conf.alarms {
+        Some(ref mut a) => a,
+        None => return false,
+    };
+    let mut used: Vec<String> = vec![];
+    let mut missing = alarms.iter().any(|a| a.id.is_none());
+    for a in alarms.iter_mut() {
+        if let Some(ref id) = a.id {
+            if used.contains(id) {
+                beprint(&format!(
+                    "duplicate alarm id '{}', generating a new one",
+                    id
+                ));
+                a.id = None;
+                missing = true;
+            } else {
+                used.push(id.clone());
+            }
+        }
+    }
+    let mut prev: Vec<&Alarm> = old
+        .and_then(|c| c.alarms.as_ref())
+        .map(|a| a.iter().collect())
+        .unwrap_or_default();
+    // every old id is taken back before any new ones are handed out,
+    // so a new alarm can't take the id of an unchanged one further down
+    for a in alarms.iter_mut().filter(|a| a.id.is_none()) {
+        if let Some(i) = prev
+            .iter()
+            .position(|p| p.id.as_ref().is_some_and(|id| !used.contains(id)) && same_alarm(p, a))
+        {
+            let id = prev.remove(i).id.clone().unwrap();
+            used.push(id.clone());
+            a.id = Some(id);
+        }
+    }
+    for a in alarms.iter_mut().filter(|a| a.id.is_none()) {
+        let id = fresh_id(&used.iter().map(|u| u.as_str()).collect::<Vec<_>>());
+        used.push(id.clone());
+        a.id = Some(id);
+    }
+    missing
+}
+
+fn find_next_rep(mut base: NaiveDate, rep: &Option<Vec<String>>) -> Option<NaiveDate> {
+    if let Some(r) = rep {
+        // Every weekday shows up within a week, so if none of the
+        // next 7 days match, the repeat list has no valid days
+        for _ in 0..7 {
+            if r.contains(&weekday_to_str(base.weekday())) {
+                return Some(base);
+            }
+            // This only fails if we run out of representable dates,
+            // at which point we have much bigger problems
+            base = base.succ_opt().unwrap();
+        }
+        None
+    } else {
+        Some(base)
+    }
+}
+
+// Stops anything ringing and removes the socket, so the next
+// pwalarmd doesn't find a stale one
+fn shutdown(
+    ringing: &mut Vec<RingingAlarm>,
+    clients: &mut HashMap<u64, client::Client>,
+    sock_path: Option<&str>,
+) -> ! {
+    systemd::notify("STOPPING=1");
+    // as far as their sockets take it without waiting
+    publish(clients, &mut vec![event(EventType::ShuttingDown, None)]);
+    // dropping a sink stops its sound
+    ringing.clear();
+    if let Some(p) = sock_path {
+        std::fs::remove_file(p).unwrap_or(());
+    }
+    std::process::exit(0)
+}
+
+// An event about alarm, if it's about one, happening now
+fn event(ty: EventType, alarm: Option<&Alarm>) -> protobuf_sock::Event {
+    let mut ev = protobuf_sock::Event::new();
+    ev.set_type(ty);
+    ev.al = protobuf::MessageField::from_option(alarm.and_then(|a| a.clone().try_into().ok()));
+    ev.time = epoch_secs(SystemTime::now());
+    ev
+}
+
+// Queues events for every subscribed client, dropping the ones
+// that have gone away
+fn publish(clients: &mut HashMap<u64, client::Client>, events: &mut Vec<protobuf_sock::Event>) {
+    for ev in events.drain(..) {
+        let mut resp = protobuf_sock::SocketResponse::new();
+        resp.set_ev(ev);
+        for c in clients.values_mut().filter(|c| c.subscribed) {
+            c.rid = None;
+            c.send(resp.clone());
+        }
+    }
+    clients.retain(|_, c| c.is_open());
+}
+
+fn epoch_secs(t: SystemTime) -> Option<i64> {
+    t.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs() as i64)
+}
+
+// Logs what's scheduled and ringing, for SIGUSR1
+fn dump_state(
+    ring: &VecDeque<LocalAlarm>,
+    snoozed: &[SnoozedAlarm],
+    timers: &[Timer],
+    ringing: &[RingingAlarm],
+) {
+    let describe = |a: &Alarm| {
+        format!(
+            "{} \"{}\"",
+            a.id.as_deref().unwrap_or("-"),
+            a.title.as_deref().unwrap_or("")
+        )
+    };
+    bprint(&format!("{} alarm(s) scheduled", ring.len()));
+    for a in ring {
+        bprint(&format!(
+            "  {} {}",
+            a.next_run.format("%Y-%m-%d %H:%M:%S"),
+            describe(&a.alarm)
+        ));
+    }
+    for s in snoozed {
+        bprint(&format!(
+            "  {} {} (snoozed)",
+            s.until.format("%Y-%m-%d %H:%M:%S"),
+            describe(&s.alarm)
+        ));
+    }
+    for t in timers {
+        bprint(&format!(
+            "  {} {} (timer)",
+            t.until.format("%Y-%m-%d %H:%M:%S"),
+            describe(&t.alarm)
+        ));
+    }
+    for r in ringing {
+        bprint(&format!("  ringing: {}", describe(&r.alarm)));
+    }
+}
+
+// Like beprint, for things that aren't errors
+fn bprint(msg: &str) {
+    eprint!("{}", "pwalarmd".yellow().bold());
+    eprintln!(": {}", msg);
+}
+
+fn beprint(msg: &str) {
+    eprint!("{}", "pwalarmd".yellow().bold());
+    eprintln!(": {}", msg.bright_red());
 }
 
 fn weekday_to_str(weekday: Weekday) -> String {
@@ -533,68 +2135,59 @@ fn weekday_to_str(weekday: Weekday) -> String {
     .to_string()
 }
 
-fn loadsnd(
-    path: String,
-) -> Result<SamplesConverter<Decoder<BufReader<File>>, f32>, Box<dyn std::error::Error>> {
+fn loadsnd(path: String) -> Result<SoundSource, Box<dyn std::error::Error>> {
     Ok(Decoder::new(BufReader::new(File::open(path)?))?.convert_samples::<f32>())
 }
 
-// TODO: evaluate whether setting nonblocking on Stream is necessary
-// (or just listener)
-fn proto_send_error(
-    err: ErrorReason,
-    sock: &mut UnixStream,
-) -> Result<(), Box<dyn std::error::Error>> {
+fn proto_send_error(err: ErrorReason, sock: &mut client::Client) {
     let mut resp = protobuf_sock::SocketResponse::new();
     let mut sr = protobuf_sock::RequestError::new();
     sr.set_er(err);
     resp.set_err(sr);
-    resp.write_to(&mut protobuf::CodedOutputStream::new(sock))?;
-    sock.flush()?;
-    sock.set_nonblocking(true)?;
-    Ok(())
+    sock.send(resp);
 }
 
-fn proto_send_success(sock: &mut UnixStream) -> Result<(), Box<dyn std::error::Error>> {
+// for errors the client should show as they are
+fn proto_send_error_msg(err: ErrorReason, msg: String, sock: &mut client::Client) {
+    let mut resp = protobuf_sock::SocketResponse::new();
+    let mut sr = protobuf_sock::RequestError::new();
+    sr.set_er(err);
+    sr.msg = Some(msg);
+    resp.set_err(sr);
+    sock.send(resp);
+}
+
+fn proto_send_success(sock: &mut client::Client) {
     let mut resp = protobuf_sock::SocketResponse::new();
     resp.set_suc(protobuf_sock::RequestSuccess::new());
-    resp.write_to(&mut protobuf::CodedOutputStream::new(sock))?;
-    sock.flush()?;
-    sock.set_nonblocking(true)?;
-    Ok(())
+    sock.send(resp);
 }
 
-fn _proto_send_data(
-    sock: &mut UnixStream,
-    dat: protobuf_sock::RequestSuccessWithData,
-) -> Result<(), Box<dyn std::error::Error>> {
+fn _proto_send_data(sock: &mut client::Client, dat: protobuf_sock::RequestSuccessWithData) {
     let mut resp = protobuf_sock::SocketResponse::new();
     resp.set_swd(dat);
-    resp.write_to(&mut protobuf::CodedOutputStream::new(sock))?;
-    sock.flush()?;
-    sock.set_nonblocking(true)?;
-    Ok(())
+    sock.send(resp);
 }
 
-fn proto_send_data_st(sock: &mut UnixStream, s: String) -> Result<(), Box<dyn std::error::Error>> {
+fn proto_send_data_st(sock: &mut client::Client, s: String) {
     let mut dat = protobuf_sock::RequestSuccessWithData::new();
     dat.set_st(s);
     _proto_send_data(sock, dat)
 }
 
-fn proto_send_data_ui(sock: &mut UnixStream, ui: u64) -> Result<(), Box<dyn std::error::Error>> {
+fn proto_send_data_ui(sock: &mut client::Client, ui: u64) {
     let mut dat = protobuf_sock::RequestSuccessWithData::new();
     dat.set_ui(ui);
     _proto_send_data(sock, dat)
 }
 
-fn proto_send_data_sui(sock: &mut UnixStream, ui: u32) -> Result<(), Box<dyn std::error::Error>> {
+fn proto_send_data_sui(sock: &mut client::Client, ui: u32) {
     let mut dat = protobuf_sock::RequestSuccessWithData::new();
     dat.set_sui(ui);
     _proto_send_data(sock, dat)
 }
 
-fn proto_send_data_bl(sock: &mut UnixStream, bl: bool) -> Result<(), Box<dyn std::error::Error>> {
+fn proto_send_data_bl(sock: &mut client::Client, bl: bool) {
     let mut dat = protobuf_sock::RequestSuccessWithData::new();
     dat.set_bl(bl);
     _proto_send_data(sock, dat)
@@ -603,24 +2196,16 @@ fn proto_send_data_bl(sock: &mut UnixStream, bl: bool) -> Result<(), Box<dyn std
 impl TryFrom<AlarmInfo> for Alarm {
     type Error = Box<dyn std::error::Error>;
     fn try_from(value: AlarmInfo) -> Result<Self, Self::Error> {
-        let t = value.time.ok_or("time cannot be absent")?;
-        Ok(Self {
+        let a = Self {
+            id: value.id,
             title: value.title,
             description: value.desc,
-            time: {
-                Datetime {
-                    date: None,
-                    time: Some(toml::value::Time {
-                        hour: min((t / 3600) as u8, 23),
-                        minute: min(((t % 3600) / 60) as u8, 59),
-                        second: min((t % 60) as u8, 59),
-                        nanosecond: 0,
-                    }),
-                    offset: None,
-                }
-            },
+            time: value
+                .time
+                .map(|t| wire_to_datetime(t, value.date))
+                .transpose()?,
             repeat: {
-                if value.repeat.len() != 0 {
+                if !value.repeat.is_empty() {
                     Some(value.repeat)
                 } else {
                     None
@@ -628,7 +2213,16 @@ impl TryFrom<AlarmInfo> for Alarm {
             },
             sound: value.sound,
             icon: value.icon,
-        })
+            snooze: value.snooze,
+            max_ring: value.max_ring,
+            volume: value.volume.map(|v| min(v, 100) as u8),
+            ramp_seconds: value.ramp_seconds,
+            enabled: value.enabled,
+            schedule: value.schedule,
+            rrule: value.rrule,
+        };
+        check_alarm(&a)?;
+        Ok(a)
     }
 }
 
@@ -637,32 +2231,414 @@ impl TryFrom<Alarm> for AlarmInfo {
     type Error = Box<dyn std::error::Error>;
     fn try_from(value: Alarm) -> Result<Self, Self::Error> {
         let mut ret = Self::new();
-        let t = value.time.time.ok_or("alarm time cannot be none")?;
+        ret.id = value.id;
         ret.title = value.title;
         ret.desc = value.description;
-        ret.time = Some(t.hour as u32 * 3600 + t.minute as u32 * 60 + t.second as u32);
+        if let Some(adt) = value.time {
+            let t = adt.time.ok_or("alarm time cannot be none")?;
+            ret.time = Some(t.hour as u32 * 3600 + t.minute as u32 * 60 + t.second as u32);
+            if let Some(d) = adt.date {
+                let nd = NaiveDate::from_ymd_opt(d.year.into(), d.month.into(), d.day.into())
+                    .ok_or("alarm date is not a real date")?;
+                ret.date = Some(date_to_days(nd));
+            }
+        }
+        ret.schedule = value.schedule;
+        ret.rrule = value.rrule;
         ret.repeat = value.repeat.unwrap_or(vec![]);
         ret.sound = value.sound;
         ret.icon = value.icon;
+        ret.snooze = value.snooze;
+        ret.max_ring = value.max_ring;
+        ret.volume = value.volume.map(|v| v.into());
+        ret.ramp_seconds = value.ramp_seconds;
+        ret.enabled = value.enabled;
         Ok(ret)
     }
 }
 
-fn determine_entry_day(atime: toml::value::Time, rep: &Option<Vec<String>>) -> Option<NaiveDate> {
-    let loc = Local::now();
-    let ld = loc.date_naive();
-    let t = NaiveTime::from_hms_opt(atime.hour.into(), atime.minute.into(), atime.second.into());
-    if let Some(z) = t {
-        find_next_rep(
-            if z > loc.time() {
-                ld
-            } else {
-                ld.succ_opt().unwrap()
-            },
-            rep,
+// The first time the alarm rings after `after`, or None if it never rings again
+fn next_run(alarm: &Alarm, after: NaiveDateTime) -> Option<NaiveDateTime> {
+    if let Some(ref s) = alarm.schedule {
+        // validated at load, see check_alarm
+        let sched = Schedule::from_str(s).ok()?;
+        // a time skipped by a DST change has no local equivalent,
+        // so start from the other side of the gap
+        let start = Local.from_local_datetime(&after).earliest().or_else(|| {
+            Local
+                .from_local_datetime(&(after + chrono::Duration::hours(1)))
+                .earliest()
+        })?;
+        return sched.after(&start).next().map(|d| d.naive_local());
+    }
+    let adt = alarm.time?;
+    let atime = adt.time?;
+    // out of legal times, but not returning Result, so return None
+    let t = NaiveTime::from_hms_opt(atime.hour.into(), atime.minute.into(), atime.second.into())?;
+    if adt.date.is_some() {
+        let ndt = dated_time(alarm)?;
+        if let Some(ref r) = alarm.rrule {
+            return rrule::RRule::from_str(r).ok()?.next_after(ndt, after);
+        }
+        // one-shot alarms only have a next run if they haven't passed yet
+        return if ndt > after { Some(ndt) } else { None };
+    }
+    find_next_rep(
+        if t > after.time() {
+            after.date()
+        } else {
+            after.date().succ_opt()?
+        },
+        &alarm.repeat,
+    )
+    .map(|d| d.and_time(t))
+}
+
+// Skips count runs of alarm from its next one, `from`. Returns the last
+// run skipped and the run after it, or None if the alarm runs out first.
+fn skip_runs(
+    alarm: &Alarm,
+    from: NaiveDateTime,
+    count: u32,
+) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    // rules are walked through in one go; the rest are cheap to step
+    let rule = alarm
+        .rrule
+        .as_deref()
+        .and_then(|r| rrule::RRule::from_str(r).ok());
+    let mut runs: Box<dyn Iterator<Item = NaiveDateTime>> = match (&rule, dated_time(alarm)) {
+        (Some(r), Some(start)) => Box::new(r.occurrences(start, from)),
+        _ => Box::new(std::iter::successors(next_run(alarm, from), |&t| {
+            next_run(alarm, t)
+        })),
+    };
+    let mut last = from;
+    let mut nr = from;
+    for _ in 0..count {
+        last = nr;
+        nr = runs.next()?;
+    }
+    Some((last, nr))
+}
+
+// A dated alarm's date and time, which is also where its rrule counts from
+fn dated_time(alarm: &Alarm) -> Option<NaiveDateTime> {
+    let adt = alarm.time?;
+    let (d, t) = (adt.date?, adt.time?);
+    NaiveDate::from_ymd_opt(d.year.into(), d.month.into(), d.day.into())?.and_hms_opt(
+        t.hour.into(),
+        t.minute.into(),
+        t.second.into(),
+    )
+}
+
+fn determine_next_run(alarm: &Alarm) -> Option<NaiveDateTime> {
+    next_run(alarm, Local::now().naive_local())
+}
+
+// secs from now, or None if that's further than a date can go
+fn from_now(secs: u64) -> Option<NaiveDateTime> {
+    let d = chrono::Duration::try_seconds(i64::try_from(secs).ok()?)?;
+    Local::now().naive_local().checked_add_signed(d)
+}
+
+// For arming the wakeup timer. Times skipped by a DST change are moved
+// an hour on, like in next_run; anything else unrepresentable wakes
+// the loop right away, which just rechecks.
+fn local_to_system(nt: NaiveDateTime) -> SystemTime {
+    Local
+        .from_local_datetime(&nt)
+        .earliest()
+        .or_else(|| {
+            Local
+                .from_local_datetime(&(nt + chrono::Duration::hours(1)))
+                .earliest()
+        })
+        .map_or_else(SystemTime::now, SystemTime::from)
+}
+
+// Checks that serde can't do while deserializing
+fn check_alarm(alarm: &Alarm) -> Result<(), String> {
+    check_repeat(alarm)?;
+    match (&alarm.time, &alarm.schedule) {
+        (Some(_), Some(_)) => Err("has both a time and a schedule".to_string()),
+        (None, None) => Err("needs either a time or a schedule".to_string()),
+        (Some(t), None) if t.time.is_none() => Err("time is missing a time of day".to_string()),
+        (Some(t), None) => match alarm.rrule {
+            Some(_) if alarm.repeat.is_some() => {
+                Err("can't use repeat together with an rrule".to_string())
+            }
+            Some(_) if t.date.is_none() => {
+                Err("needs a date in time to start its rrule from".to_string())
+            }
+            Some(ref r) => rrule::RRule::from_str(r)
+                .map(|_| ())
+                .map_err(|e| format!("has an invalid rrule: {}", e)),
+            None => Ok(()),
+        },
+        (None, Some(_)) if alarm.repeat.is_some() || alarm.rrule.is_some() => {
+            Err("can't use repeat or rrule together with a schedule".to_string())
+        }
+        (None, Some(z)) => Schedule::from_str(z)
+            .map(|_| ())
+            .map_err(|e| format!("has an invalid schedule: {}", e)),
+    }
+}
+
+fn check_repeat(alarm: &Alarm) -> Result<(), String> {
+    for d in alarm.repeat.iter().flatten() {
+        if !WEEKDAYS.contains(&d.as_str()) {
+            return Err(format!(
+                "has an unknown day \"{}\" in repeat (use {})",
+                d,
+                WEEKDAYS.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Times cross the socket as seconds since midnight,
+// and dates as days since the Unix epoch
+fn wire_to_datetime(t: u32, date: Option<i32>) -> Result<Datetime, Box<dyn std::error::Error>> {
+    let date = match date {
+        Some(d) => {
+            // the most a TOML date can hold
+            let nd = days_to_date(d)
+                .filter(|nd| (0..=9999).contains(&nd.year()))
+                .ok_or("date out of range")?;
+            Some(toml::value::Date {
+                year: nd.year() as u16,
+                month: nd.month() as u8,
+                day: nd.day() as u8,
+            })
+        }
+        None => None,
+    };
+    if t >= 24 * 3600 {
+        return Err("time of day out of range".into());
+    }
+    Ok(Datetime {
+        date,
+        time: Some(toml::value::Time {
+            hour: (t / 3600) as u8,
+            minute: ((t % 3600) / 60) as u8,
+            second: (t % 60) as u8,
+            nanosecond: 0,
+        }),
+        offset: None,
+    })
+}
+
+fn date_to_days(d: NaiveDate) -> i32 {
+    (d - NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()).num_days() as i32
+}
+
+fn days_to_date(days: i32) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(1970, 1, 1)?.checked_add_signed(chrono::Duration::days(days.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(alarms: &[&str]) -> Config {
+        let mut s = "[General]\nnotify = false\n".to_string();
+        for t in alarms {
+            s += &format!("[[Alarm]]\ntitle = \"{}\"\ntime = 07:00:00\n", t);
+        }
+        toml::from_str(&s).unwrap()
+    }
+
+    fn ids(c: &Config) -> Vec<(&str, &str)> {
+        c.alarms
+            .iter()
+            .flatten()
+            .map(|a| (a.title.as_deref().unwrap(), a.id.as_deref().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn wire_times_are_checked() {
+        let t = wire_to_datetime(23 * 3600 + 59 * 60 + 59, None).unwrap();
+        assert_eq!(t.to_string(), "23:59:59");
+        assert!(wire_to_datetime(24 * 3600, None).is_err());
+        assert!(wire_to_datetime(99 * 3600 + 99 * 60, None).is_err());
+    }
+
+    #[test]
+    fn wire_dates_fit_in_toml() {
+        let day = |y, m, d| date_to_days(NaiveDate::from_ymd_opt(y, m, d).unwrap());
+        let t = wire_to_datetime(0, Some(day(9999, 12, 31))).unwrap();
+        assert_eq!(t.to_string(), "9999-12-31T00:00:00");
+        assert!(wire_to_datetime(0, Some(day(10000, 1, 1))).is_err());
+        assert!(wire_to_datetime(0, Some(day(-1, 12, 31))).is_err());
+        assert!(wire_to_datetime(0, Some(i32::MAX)).is_err());
+    }
+
+    #[test]
+    fn ids_survive_a_new_alarm_on_top() {
+        let mut old = config(&["A", "B", "C"]);
+        assign_ids(&mut old, None);
+        assert_eq!(ids(&old), [("A", "1"), ("B", "2"), ("C", "3")]);
+        let mut new = config(&["N", "A", "B", "C"]);
+        assign_ids(&mut new, Some(&old));
+        assert_eq!(ids(&new), [("N", "4"), ("A", "1"), ("B", "2"), ("C", "3")]);
+    }
+
+    #[test]
+    fn generated_ids_survive_a_restart() {
+        let text = "[General]\nnotify = false\n\
+                    [[Alarm]]\ntitle = \"A\"\ntime = 07:00:00\n\
+                    [[Alarm]]\ntitle = \"B\"\ntime = 08:00:00\n";
+        // what the first start writes back
+        let text = saved(text, |_| {});
+        let mut c: Config = toml::from_str(&text).unwrap();
+        assert!(!assign_ids(&mut c, None));
+        // an alarm added on top before the next start gets a new id
+        let text = text.replacen(
+            "[[Alarm]]",
+            "[[Alarm]]\ntitle = \"N\"\ntime = 06:00:00\n[[Alarm]]",
+            1,
+        );
+        let mut c: Config = toml::from_str(&text).unwrap();
+        assert!(assign_ids(&mut c, None));
+        assert_eq!(ids(&c), [("N", "3"), ("A", "1"), ("B", "2")]);
+    }
+
+    #[test]
+    fn ringing_again_starts_over() {
+        let rings = |id: &str, title: &str| RingingAlarm {
+            alarm: alarm(id, title),
+            sink: Sink::new_idle().0,
+            deadline: None,
+        };
+        let mut ringing = vec![];
+        add_ringing(&mut ringing, rings("a", "First"));
+        add_ringing(&mut ringing, rings("b", "Other"));
+        add_ringing(&mut ringing, rings("a", "Again"));
+        let titles: Vec<_> = ringing.iter().map(|r| alarm_name(&r.alarm)).collect();
+        assert_eq!(titles, ["Other", "Again"]);
+    }
+
+    #[test]
+    fn duplicate_ids_are_replaced() {
+        let mut c: Config = toml::from_str(
+            "[General]\nnotify = false\n\
+             [[Alarm]]\nid = \"x\"\ntime = 07:00:00\n\
+             [[Alarm]]\nid = \"x\"\ntime = 08:00:00\n",
         )
-    } else {
-        // out of legal times, but not returning Result, so return None
-        None
+        .unwrap();
+        assign_ids(&mut c, None);
+        let ids: Vec<_> = c.alarms.iter().flatten().map(|a| a.id.clone()).collect();
+        assert_eq!(ids, [Some("x".to_string()), Some("1".to_string())]);
+    }
+
+    // Loads text like main does, lets edit change the config, and saves it
+    // back over text.
+    fn saved(text: &str, edit: impl FnOnce(&mut Config)) -> String {
+        let mut c: Config = toml::from_str(text).unwrap();
+        assign_ids(&mut c, None);
+        let file_ids = alarm_ids(&c);
+        edit(&mut c);
+        let mut doc: DocumentMut = text.parse().unwrap();
+        update_config(&mut doc, &c, &file_ids).unwrap();
+        doc.to_string()
+    }
+
+    fn alarm(id: &str, title: &str) -> Alarm {
+        let mut c = config(&[title]);
+        let mut a = c.alarms.take().unwrap().remove(0);
+        a.id = Some(id.to_string());
+        a
+    }
+
+    #[test]
+    fn saving_keeps_comments_and_key_order() {
+        let text = "# my alarms\n\
+                    [General]\n\
+                    notify = false # quiet\n\
+                    sound = 'ring.mp3'\n\
+                    \n\
+                    # weekdays\n\
+                    [[Alarm]]\n\
+                    time = 07:00:00 # early\n\
+                    title = 'Work'\n\
+                    \n\
+                    [[Alarm]]\n\
+                    title = \"Gym\"\n\
+                    time = 18:00:00\n";
+        let out = saved(text, |c| {
+            c.general.notify = true;
+            c.alarms.as_mut().unwrap()[1].title = Some("Swim".to_string());
+        });
+        assert_eq!(
+            out,
+            "# my alarms\n\
+             [General]\n\
+             notify = true # quiet\n\
+             sound = 'ring.mp3'\n\
+             \n\
+             # weekdays\n\
+             [[Alarm]]\n\
+             time = 07:00:00 # early\n\
+             title = 'Work'\n\
+             id = \"1\"\n\
+             \n\
+             [[Alarm]]\n\
+             title = \"Swim\"\n\
+             time = 18:00:00\n\
+             id = \"2\"\n"
+        );
+    }
+
+    #[test]
+    fn alarm_tables_stay_put() {
+        let text = "[General]\nnotify = false\n\
+                    [[Alarm]]\nid = \"a\"\ntime = 07:00:00\n\
+                    [[Alarm]]\nid = \"b\"\ntime = 08:00:00\n\
+                    [[Calendar]]\npath = \"cal.ics\"\n\
+                    # the end\n";
+        let out = saved(text, |c| {
+            let alarms = c.alarms.as_mut().unwrap();
+            alarms.insert(0, alarm("n", "New"));
+            alarms.remove(2);
+            alarms.push(alarm("m", "More"));
+        });
+        assert_eq!(
+            out,
+            "[General]\nnotify = false\n\
+             \n[[Alarm]]\nid = \"n\"\ntitle = \"New\"\ntime = 07:00:00\n\
+             [[Alarm]]\nid = \"a\"\ntime = 07:00:00\n\
+             \n[[Alarm]]\nid = \"m\"\ntitle = \"More\"\ntime = 07:00:00\n\
+             [[Calendar]]\npath = \"cal.ics\"\n\
+             # the end\n"
+        );
+        // nothing left to save drops the array but nothing around it
+        let out = saved(text, |c| c.alarms = None);
+        assert_eq!(
+            out,
+            "[General]\nnotify = false\n\
+             [[Calendar]]\npath = \"cal.ics\"\n\
+             # the end\n"
+        );
+    }
+
+    #[test]
+    fn merge_table_round_trips() {
+        let text = "b = [1, 2] # list\na = 'x'\nc = 1970-01-01\n";
+        let doc: DocumentMut = text.parse().unwrap();
+        let mut t = doc.as_table().clone();
+        // merging a table's own values changes nothing, quoting included
+        merge_table(&mut t, doc.as_table());
+        assert_eq!(t.to_string(), text);
+        let other: DocumentMut = "a = 'y'\nb = [1, 3]\nd = true\n".parse().unwrap();
+        merge_table(&mut t, other.as_table());
+        assert_eq!(t.to_string(), "b = [1, 3] # list\na = 'y'\nd = true\n");
+        merge_table(&mut t, doc.as_table());
+        assert_eq!(
+            t.to_string(),
+            "b = [1, 2] # list\na = 'x'\nc = 1970-01-01\n"
+        );
     }
 }
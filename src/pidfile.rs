@@ -0,0 +1,50 @@
+// Keeps to one pwalarmd per user: the running daemon holds a flock on
+// its pid file for as long as it lives, and the kernel drops the lock
+// when it exits, however that happens
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+pub struct PidFile {
+    file: File,
+}
+
+impl PidFile {
+    // None if another process holds the lock
+    pub fn lock(path: &Path) -> io::Result<Option<Self>> {
+        // not truncated yet, so the other process's pid can still be read
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::WouldBlock {
+                Ok(None)
+            } else {
+                Err(e)
+            };
+        }
+        Ok(Some(Self { file }))
+    }
+
+    // Has to be called again after daemonizing, which changes the pid
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.flush()
+    }
+}
+
+// The pid the running instance wrote, for error messages
+pub fn holder(path: &Path) -> Option<u32> {
+    let mut s = String::new();
+    File::open(path).ok()?.read_to_string(&mut s).ok()?;
+    s.trim().parse().ok()
+}
@@ -0,0 +1,510 @@
+// A subset of RFC 5545 recurrence rules, enough for alarms:
+// FREQ (DAILY, WEEKLY, MONTHLY, YEARLY), INTERVAL, COUNT, UNTIL,
+// BYDAY (with ordinals), BYMONTHDAY, BYMONTH and WKST.
+// Alarms ring at one time of day, so the sub-daily parts
+// (BYHOUR, BYMINUTE, ...) aren't supported.
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, Local, Months, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+
+// How far past `after` to look before giving up on a rule
+// that never matches (e.g. BYMONTH=2;BYMONTHDAY=30)
+const MAX_YEARS_AHEAD: i32 = 100;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Debug)]
+pub struct RRule {
+    freq: Freq,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<NaiveDateTime>,
+    // (ordinal, weekday); an ordinal of -1 is the last one in the period
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+    wkst: Weekday,
+}
+
+impl FromStr for RRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix("RRULE:").unwrap_or(s);
+        let mut freq = None;
+        let mut rule = RRule {
+            freq: Freq::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+            wkst: Weekday::Mon,
+        };
+        for part in s.split(';').filter(|p| !p.is_empty()) {
+            let (k, v) = part
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not KEY=VALUE", part))?;
+            match k.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match v.to_ascii_uppercase().as_str() {
+                        "DAILY" => Freq::Daily,
+                        "WEEKLY" => Freq::Weekly,
+                        "MONTHLY" => Freq::Monthly,
+                        "YEARLY" => Freq::Yearly,
+                        _ => return Err(format!("unsupported FREQ '{}'", v)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = v
+                        .parse()
+                        .ok()
+                        .filter(|i| *i > 0)
+                        .ok_or_else(|| format!("invalid INTERVAL '{}'", v))?
+                }
+                "COUNT" => {
+                    rule.count = Some(v.parse().map_err(|_| format!("invalid COUNT '{}'", v))?)
+                }
+                "UNTIL" => rule.until = Some(parse_until(v)?),
+                "BYDAY" => {
+                    for d in v.split(',') {
+                        rule.by_day.push(parse_byday(d)?);
+                    }
+                }
+                "BYMONTHDAY" => {
+                    for d in v.split(',') {
+                        rule.by_month_day.push(
+                            d.parse()
+                                .ok()
+                                .filter(|n: &i32| (1..=31).contains(&n.abs()))
+                                .ok_or_else(|| format!("invalid BYMONTHDAY '{}'", d))?,
+                        );
+                    }
+                }
+                "BYMONTH" => {
+                    for m in v.split(',') {
+                        rule.by_month.push(
+                            m.parse()
+                                .ok()
+                                .filter(|n| (1..=12).contains(n))
+                                .ok_or_else(|| format!("invalid BYMONTH '{}'", m))?,
+                        );
+                    }
+                }
+                "WKST" => {
+                    rule.wkst = parse_weekday(v).ok_or_else(|| format!("invalid WKST '{}'", v))?
+                }
+                _ => return Err(format!("unsupported rule part '{}'", k)),
+            }
+        }
+        rule.freq = freq.ok_or("FREQ is required")?;
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err("COUNT and UNTIL can't be used together".to_string());
+        }
+        if !rule.by_month_day.is_empty() && rule.freq == Freq::Weekly {
+            return Err("BYMONTHDAY can't be used with FREQ=WEEKLY".to_string());
+        }
+        if rule.by_day.iter().any(|(n, _)| n.is_some())
+            && !(rule.freq == Freq::Monthly || rule.freq == Freq::Yearly)
+        {
+            return Err("BYDAY ordinals need FREQ=MONTHLY or FREQ=YEARLY".to_string());
+        }
+        Ok(rule)
+    }
+}
+
+impl RRule {
+    // The first occurrence strictly after `after`, for a rule starting at
+    // dtstart. Occurrences take their time of day from dtstart.
+    pub fn next_after(
+        &self,
+        dtstart: NaiveDateTime,
+        after: NaiveDateTime,
+    ) -> Option<NaiveDateTime> {
+        self.occurrences(dtstart, after).next()
+    }
+
+    // Every occurrence strictly after `after`, in order. Each one carries
+    // on from the one before, where calling next_after in a loop would
+    // go back to dtstart every time.
+    pub fn occurrences(&self, dtstart: NaiveDateTime, after: NaiveDateTime) -> Occurrences<'_> {
+        Occurrences {
+            rule: self,
+            dtstart,
+            after,
+            limit: after.date().year() + MAX_YEARS_AHEAD,
+            seen: 0,
+            period: Some(0),
+            days: vec![].into_iter(),
+        }
+    }
+
+    // The first day of the nth period counted from start, and the days in
+    // that period matching the rule, in order
+    fn period(&self, start: NaiveDate, n: u32) -> Option<(NaiveDate, Vec<NaiveDate>)> {
+        let step = n.checked_mul(self.interval)?;
+        match self.freq {
+            Freq::Daily => {
+                let d = start.checked_add_signed(Duration::days(step.into()))?;
+                Some((d, if self.matches(d) { vec![d] } else { vec![] }))
+            }
+            Freq::Weekly => {
+                let back = (7 + start.weekday().num_days_from_monday()
+                    - self.wkst.num_days_from_monday())
+                    % 7;
+                let first = start
+                    .checked_add_signed(Duration::days(i64::from(step) * 7 - i64::from(back)))?;
+                let days: Vec<NaiveDate> = first
+                    .iter_days()
+                    .take(7)
+                    .filter(|d| {
+                        if self.by_day.is_empty() {
+                            d.weekday() == start.weekday()
+                        } else {
+                            self.by_day.iter().any(|(_, w)| *w == d.weekday())
+                        }
+                    })
+                    .filter(|d| self.by_month.is_empty() || self.by_month.contains(&d.month()))
+                    .collect();
+                Some((first, days))
+            }
+            Freq::Monthly => {
+                let first = start.with_day(1)?.checked_add_months(Months::new(step))?;
+                if !self.by_month.is_empty() && !self.by_month.contains(&first.month()) {
+                    return Some((first, vec![]));
+                }
+                Some((first, self.month_days(first, start.day())))
+            }
+            Freq::Yearly => {
+                let first = NaiveDate::from_ymd_opt(start.year().checked_add(step as i32)?, 1, 1)?;
+                let months: Vec<u32> = if !self.by_month.is_empty() {
+                    self.by_month.clone()
+                } else if !self.by_month_day.is_empty() {
+                    (1..=12).collect()
+                } else if !self.by_day.is_empty() {
+                    // weekdays without months are counted across the whole year
+                    let last = NaiveDate::from_ymd_opt(first.year(), 12, 31)?;
+                    return Some((first, weekdays_in(first, last, &self.by_day)));
+                } else {
+                    vec![start.month()]
+                };
+                let mut days = vec![];
+                for m in months {
+                    days.extend(self.month_days(first.with_month(m)?, start.day()));
+                }
+                days.sort();
+                days.dedup();
+                Some((first, days))
+            }
+        }
+    }
+
+    // Matching days within the month starting at first
+    fn month_days(&self, first: NaiveDate, start_day: u32) -> Vec<NaiveDate> {
+        let last = last_of_month(first);
+        let weekdays = weekdays_in(first, last, &self.by_day);
+        let mut days: Vec<NaiveDate> = if !self.by_month_day.is_empty() {
+            self.by_month_day
+                .iter()
+                .filter_map(|n| {
+                    if *n > 0 {
+                        first.with_day(*n as u32)
+                    } else {
+                        last.checked_sub_signed(Duration::days(i64::from(-n - 1)))
+                            .filter(|d| d.month() == first.month())
+                    }
+                })
+                // BYDAY only narrows the days BYMONTHDAY picked
+                .filter(|d| self.by_day.is_empty() || weekdays.contains(d))
+                .collect()
+        } else if !self.by_day.is_empty() {
+            weekdays
+        } else {
+            // months without the start day (e.g. the 31st) are skipped
+            first.with_day(start_day).into_iter().collect()
+        };
+        days.sort();
+        days.dedup();
+        days
+    }
+
+    // Daily rules have nothing to expand, only filters
+    fn matches(&self, d: NaiveDate) -> bool {
+        (self.by_month.is_empty() || self.by_month.contains(&d.month()))
+            && (self.by_day.is_empty() || self.by_day.iter().any(|(_, w)| *w == d.weekday()))
+            && (self.by_month_day.is_empty()
+                || self.by_month_day.iter().any(|n| {
+                    if *n > 0 {
+                        d.day() == *n as u32
+                    } else {
+                        last_of_month(d).day() as i32 + n + 1 == d.day() as i32
+                    }
+                }))
+    }
+}
+
+// The days between first and last (inclusive) matching BYDAY; with an
+// ordinal, only the nth (or nth-from-last) such weekday counts
+fn weekdays_in(
+    first: NaiveDate,
+    last: NaiveDate,
+    by_day: &[(Option<i32>, Weekday)],
+) -> Vec<NaiveDate> {
+    let mut days = vec![];
+    for (n, w) in by_day {
+        let all: Vec<NaiveDate> = first
+            .iter_days()
+            .take_while(|d| *d <= last)
+            .filter(|d| d.weekday() == *w)
+            .collect();
+        match n {
+            None => days.extend(all),
+            Some(n) if *n > 0 => days.extend(all.get(*n as usize - 1)),
+            Some(n) => days.extend(
+                all.len()
+                    .checked_sub(n.unsigned_abs() as usize)
+                    .map(|i| all[i]),
+            ),
+        }
+    }
+    days.sort();
+    days.dedup();
+    days
+}
+
+fn last_of_month(d: NaiveDate) -> NaiveDate {
+    d.with_day(1)
+        .and_then(|f| f.checked_add_months(Months::new(1)))
+        .and_then(|f| f.pred_opt())
+        .unwrap_or(d)
+}
+
+fn parse_weekday(s: &str) -> Option<Weekday> {
+    Some(match s.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+// [+-]nWD, e.g. MO, 2TU, -1FR
+fn parse_byday(s: &str) -> Result<(Option<i32>, Weekday), String> {
+    let err = || format!("invalid BYDAY '{}'", s);
+    // the weekday is the last two characters, which needn't be bytes
+    let at = s.char_indices().rev().nth(1).ok_or_else(err)?.0;
+    let (n, w) = s.split_at(at);
+    let w = parse_weekday(w).ok_or_else(err)?;
+    if n.is_empty() {
+        return Ok((None, w));
+    }
+    match n.parse::<i32>() {
+        Ok(n) if n != 0 && n.abs() <= 53 => Ok((Some(n), w)),
+        _ => Err(err()),
+    }
+}
+
+// A date (inclusive of the whole day), a local date-time,
+// or a UTC date-time ending in Z
+fn parse_until(s: &str) -> Result<NaiveDateTime, String> {
+    let err = || format!("invalid UNTIL '{}'", s);
+    if let Some(u) = s.strip_suffix('Z') {
+        let dt = NaiveDateTime::parse_from_str(u, "%Y%m%dT%H%M%S").map_err(|_| err())?;
+        return Ok(Local.from_utc_datetime(&dt).naive_local());
+    }
+    if let Ok(dt) = NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%S") {
+        return Ok(dt);
+    }
+    NaiveDate::parse_from_str(s, "%Y%m%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(23, 59, 59))
+        .ok_or_else(err)
+}
+
+pub struct Occurrences<'a> {
+    rule: &'a RRule,
+    dtstart: NaiveDateTime,
+    after: NaiveDateTime,
+    // the last year worth looking in, MAX_YEARS_AHEAD past `after`
+    limit: i32,
+    // occurrences so far, for COUNT
+    seen: u32,
+    // the next period to look in, None once they run out
+    period: Option<u32>,
+    // what's left of the current period's days
+    days: std::vec::IntoIter<NaiveDate>,
+}
+
+impl Iterator for Occurrences<'_> {
+    type Item = NaiveDateTime;
+    fn next(&mut self) -> Option<NaiveDateTime> {
+        let time = self.dtstart.time();
+        loop {
+            for d in self.days.by_ref() {
+                let dt = d.and_time(time);
+                if dt < self.dtstart {
+                    continue;
+                }
+                if self.rule.until.is_some_and(|u| dt > u) {
+                    return None;
+                }
+                self.seen += 1;
+                if self.rule.count.is_some_and(|c| self.seen > c) {
+                    return None;
+                }
+                if dt > self.after {
+                    self.after = dt;
+                    self.limit = dt.year() + MAX_YEARS_AHEAD;
+                    return Some(dt);
+                }
+            }
+            let n = self.period?;
+            let (first, days) = self.rule.period(self.dtstart.date(), n)?;
+            if first.year() > self.limit {
+                return None;
+            }
+            self.days = days.into_iter();
+            self.period = n.checked_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    // the first n occurrences strictly after dtstart's own
+    fn next_n(rule: &str, dtstart: &str, n: usize) -> Vec<NaiveDateTime> {
+        let r = RRule::from_str(rule).unwrap();
+        let mut at = dt(dtstart);
+        let mut out = vec![];
+        while out.len() < n {
+            match r.next_after(dt(dtstart), at) {
+                Some(next) => {
+                    out.push(next);
+                    at = next;
+                }
+                None => break,
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn occurrences_carry_on() {
+        for rule in [
+            "FREQ=DAILY;INTERVAL=3",
+            "FREQ=WEEKLY;BYDAY=MO,TH;COUNT=40",
+            "FREQ=MONTHLY;BYMONTHDAY=31",
+            "FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=29;UNTIL=21000101T000000",
+        ] {
+            let r = RRule::from_str(rule).unwrap();
+            let start = dt("2024-01-01 06:30");
+            let all: Vec<_> = r.occurrences(start, start).take(60).collect();
+            assert_eq!(all, next_n(rule, "2024-01-01 06:30", 60), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn every_other_tuesday() {
+        // 2024-01-02 is a Tuesday
+        assert_eq!(
+            next_n("FREQ=WEEKLY;INTERVAL=2;BYDAY=TU", "2024-01-02 07:00", 2),
+            [dt("2024-01-16 07:00"), dt("2024-01-30 07:00")]
+        );
+    }
+
+    #[test]
+    fn last_friday_of_the_month() {
+        assert_eq!(
+            next_n("FREQ=MONTHLY;BYDAY=-1FR", "2024-01-01 08:00", 3),
+            [
+                dt("2024-01-26 08:00"),
+                dt("2024-02-23 08:00"),
+                dt("2024-03-29 08:00")
+            ]
+        );
+    }
+
+    #[test]
+    fn byday_ordinals() {
+        assert_eq!(
+            next_n("FREQ=MONTHLY;BYDAY=2TU", "2024-01-01 08:00", 2),
+            [dt("2024-01-09 08:00"), dt("2024-02-13 08:00")]
+        );
+        // without BYMONTH, the first Monday of the whole year
+        assert_eq!(
+            next_n("FREQ=YEARLY;BYDAY=1MO", "2024-01-01 08:00", 1),
+            [dt("2025-01-06 08:00")]
+        );
+    }
+
+    #[test]
+    fn count_includes_dtstart() {
+        let all = next_n(
+            "FREQ=MONTHLY;BYMONTHDAY=15;COUNT=10",
+            "2024-01-15 07:00",
+            20,
+        );
+        assert_eq!(all.len(), 9);
+        assert_eq!(all[0], dt("2024-02-15 07:00"));
+        assert_eq!(all[8], dt("2024-10-15 07:00"));
+    }
+
+    #[test]
+    fn until_is_inclusive() {
+        assert_eq!(
+            next_n("FREQ=DAILY;UNTIL=20240103", "2024-01-01 07:00", 5),
+            [dt("2024-01-02 07:00"), dt("2024-01-03 07:00")]
+        );
+        assert_eq!(
+            next_n("FREQ=DAILY;UNTIL=20240102T070000", "2024-01-01 07:00", 5),
+            [dt("2024-01-02 07:00")]
+        );
+    }
+
+    #[test]
+    fn impossible_rule_ends() {
+        assert!(next_n("FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=30", "2024-01-01 07:00", 1).is_empty());
+    }
+
+    #[test]
+    fn bad_input() {
+        for r in [
+            "",
+            "BYDAY=MO",
+            "FREQ=HOURLY",
+            "FREQ=DAILY;INTERVAL=0",
+            "FREQ=DAILY;COUNT=x",
+            "FREQ=DAILY;COUNT=2;UNTIL=20240101",
+            "FREQ=WEEKLY;BYMONTHDAY=1",
+            "FREQ=WEEKLY;BYDAY=2MO",
+            "FREQ=MONTHLY;BYDAY=0MO",
+            "FREQ=MONTHLY;BYDAY=54MO",
+            "FREQ=MONTHLY;BYDAY=M",
+            "FREQ=WEEKLY;BYDAY=aéa",
+            "FREQ=WEEKLY;BYDAY=é",
+            "FREQ=MONTHLY;BYMONTHDAY=32",
+            "FREQ=YEARLY;BYMONTH=13",
+            "FREQ=DAILY;UNTIL=tomorrow",
+            "FREQ=DAILY;BYHOUR=7",
+            "FREQ",
+        ] {
+            assert!(RRule::from_str(r).is_err(), "{}", r);
+        }
+    }
+}
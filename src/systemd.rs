@@ -0,0 +1,150 @@
+// Running as a systemd user service: the listening socket can be
+// handed over by a .socket unit (LISTEN_FDS), and the service manager
+// is told about startup, status and liveness through sd_notify.
+// See sd_listen_fds(3) and sd_notify(3); libsystemd isn't needed for this.
+use std::{
+    io,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+// The first inherited fd; 0-2 are stdio
+const LISTEN_FDS_START: RawFd = 3;
+
+// Set by listener, which clears LISTEN_PID from the environment
+static ACTIVATED: AtomicBool = AtomicBool::new(false);
+
+// Whether systemd started us, in which case it also does the
+// daemonizing, so pwalarmd mustn't fork
+pub fn managed() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some() || ACTIVATED.load(Ordering::Relaxed)
+}
+
+// The listening socket passed in by socket activation, if any. It
+// clears LISTEN_* from the environment, so it has to be called once,
+// before anything starts a thread.
+pub fn listener() -> io::Result<Option<UnixListener>> {
+    if !for_us("LISTEN_PID") {
+        return Ok(None);
+    }
+    ACTIVATED.store(true, Ordering::Relaxed);
+    let n = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok());
+    // they'd be passed on to anything we start otherwise
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if n.map_or(true, |n| n < 1) {
+        return Ok(None);
+    }
+    if !is_unix_stream(LISTEN_FDS_START) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {} is not a Unix stream socket", LISTEN_FDS_START),
+        ));
+    }
+    unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) }))
+}
+
+// A misconfigured .socket unit could pass a TCP socket, a FIFO,
+// or nothing at all
+fn is_unix_stream(fd: RawFd) -> bool {
+    let opt = |name| {
+        let mut v: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                (&mut v as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+        (r == 0).then_some(v)
+    };
+    opt(libc::SO_DOMAIN) == Some(libc::AF_UNIX) && opt(libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+}
+
+// How often to send WATCHDOG=1, if the unit sets WatchdogSec;
+// half the timeout, as sd_watchdog_enabled(3) suggests
+pub fn watchdog_interval() -> Option<Duration> {
+    if std::env::var_os("WATCHDOG_PID").is_some() && !for_us("WATCHDOG_PID") {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+// Sends state (e.g. "READY=1") to the service manager. Does nothing
+// outside systemd, and failures are ignored, like sd_notify's are.
+pub fn notify(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let sock = match UnixDatagram::unbound() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    // a leading @ means an abstract socket
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    if let Ok(addr) = addr {
+        sock.send_to_addr(state.as_bytes(), &addr).unwrap_or(0);
+    }
+}
+
+// Whether the pid in var is ours, as systemd sets it for the process
+// it means these variables for
+fn for_us(var: &str) -> bool {
+    std::env::var(var)
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok())
+        .is_some_and(|p| p == std::process::id())
+}
+
+// Unit files for running pwalarmd as a socket-activated user service,
+// for --print-unit
+pub fn units(exe: &str) -> String {
+    format!(
+        "# ~/.config/systemd/user/pwalarmd.service
+[Unit]
+Description=pwalarmd alarm daemon
+Requires=pwalarmd.socket
+After=pwalarmd.socket
+
+[Service]
+Type=notify
+ExecStart={}
+ExecReload=/bin/kill -HUP $MAINPID
+WatchdogSec=60
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+
+# ~/.config/systemd/user/pwalarmd.socket
+[Unit]
+Description=pwalarmd control socket
+
+[Socket]
+ListenStream=%t/pwalarmd/pwalarmd.sock
+SocketMode=0600
+DirectoryMode=0700
+
+[Install]
+WantedBy=sockets.target
+",
+        exe
+    )
+}